@@ -1,42 +1,288 @@
-use cargo_toml2::Profile;
-use std::path::PathBuf;
+use anyhow::{anyhow, Error};
+use cargo_sysroot::{Features, StdFeature};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 use structopt::{clap::AppSettings, StructOpt};
 
 #[derive(StructOpt, Debug)]
 pub struct Sysroot {
-    /// Path to `Cargo.toml`
-    #[structopt(long, default_value = "./Cargo.toml")]
-    pub manifest_path: PathBuf,
+    #[structopt(flatten)]
+    pub common: Common,
+
+    /// Disable .cargo/config generation
+    #[structopt(long)]
+    pub no_config: bool,
+
+    /// Rebuild the sysroot, even if it's up to date.
+    #[structopt(long)]
+    pub force: bool,
+
+    #[structopt(subcommand)]
+    pub cmd: Option<Command>,
+}
+
+/// Options shared by every subcommand.
+///
+/// These are accepted both before and after the subcommand name.
+#[derive(StructOpt, Debug)]
+pub struct Common {
+    /// Path to `Cargo.toml` [default: ./Cargo.toml]
+    #[structopt(long)]
+    pub manifest_path: Option<PathBuf>,
 
-    /// Path to target directory.
-    #[structopt(long, default_value = "./target/sysroot/target")]
-    pub target_dir: PathBuf,
+    /// Path to the target directory used to build the sysroot crates.
+    ///
+    /// Defaults to `target` in the sysroot directory.
+    #[structopt(long)]
+    pub target_dir: Option<PathBuf>,
 
     /// Path to sysroot directory.
-    #[structopt(long, default_value = "./target/sysroot")]
-    pub sysroot_dir: PathBuf,
+    ///
+    /// Uses the value from `package.metadata.cargo-sysroot.output` as a
+    /// default, or `./target/sysroot`.
+    #[structopt(long)]
+    pub sysroot_dir: Option<PathBuf>,
 
     /// Target to build for.
     ///
+    /// May be passed multiple times to build for several targets.
+    ///
     /// Uses the value from `package.metadata.cargo-sysroot.target` as a
     /// default.
+    #[structopt(long, number_of_values = 1)]
+    pub target: Vec<PathBuf>,
+
+    /// Which sysroot crates to build.
+    ///
+    /// Uses the value from `package.metadata.cargo-sysroot.crates` as a
+    /// default, or `alloc`.
+    #[structopt(long, possible_values = &["core", "compiler-builtins", "alloc", "std", "test"])]
+    pub crates: Option<cargo_sysroot::Sysroot>,
+
+    /// Features to enable, in addition to those from
+    /// `package.metadata.cargo-sysroot.features`.
+    ///
+    /// May be passed multiple times.
+    #[structopt(long = "features", possible_values = &["mem", "c", "no-asm"], number_of_values = 1)]
+    pub features: Vec<Features>,
+
+    /// Do not enable the default `mem` feature.
     #[structopt(long)]
-    pub target: Option<PathBuf>,
+    pub no_default_features: bool,
 
-    /// Disable .cargo/config generation
+    /// `std` features to enable, in addition to those from
+    /// `package.metadata.cargo-sysroot.std-features`.
+    ///
+    /// Requires `--crates std`. May be passed multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub std_features: Vec<StdFeature>,
+
+    /// Library crates from the rust sources to build, in addition to
+    /// `--crates` and `package.metadata.cargo-sysroot.extra-crates`.
+    ///
+    /// For example `panic_abort`. May be passed multiple times.
+    #[structopt(long, number_of_values = 1)]
+    pub extra_crates: Vec<String>,
+
+    /// Cargo profile to build the sysroot crates with.
+    ///
+    /// This can be `dev`, `release`, or a custom profile defined in
+    /// `Cargo.toml`. Defaults to `release`.
     #[structopt(long)]
-    pub no_config: bool,
+    pub profile: Option<String>,
+
+    /// Build without accessing the network.
+    #[structopt(long)]
+    pub offline: bool,
+
+    /// Require the sysroots Cargo.lock to be up to date, and don't access the
+    /// network.
+    #[structopt(long)]
+    pub frozen: bool,
+
+    /// `Cargo.lock` to build the sysroot with.
+    ///
+    /// Uses the value from `package.metadata.cargo-sysroot.lockfile` as a
+    /// default, or the `Cargo.lock` from the rust sources.
+    #[structopt(long)]
+    pub lockfile: Option<PathBuf>,
+
+    /// Require the lockfile to be up to date.
+    ///
+    /// This requires `--lockfile`.
+    #[structopt(long)]
+    pub locked: bool,
+
+    /// Get the sysroot crates dependencies from this directory of vendored
+    /// crates, as created by `cargo vendor`, instead of crates.io.
+    #[structopt(long, conflicts_with = "local-registry")]
+    pub vendor_dir: Option<PathBuf>,
+
+    /// Get the sysroot crates dependencies from this local registry,
+    /// instead of crates.io.
+    #[structopt(long)]
+    pub local_registry: Option<PathBuf>,
 
     /// Path to the rust sources.
     ///
-    /// If not specified, uses the `rust-src` component from rustup.
+    /// Uses the value from `package.metadata.cargo-sysroot.rust-src` as a
+    /// default, or the `rust-src` component from rustup.
     #[structopt(long)]
     pub rust_src_dir: Option<PathBuf>,
+}
+
+impl Common {
+    /// Path to `Cargo.toml`
+    pub fn manifest_path(&self) -> &Path {
+        self.manifest_path
+            .as_deref()
+            .unwrap_or_else(|| Path::new("./Cargo.toml"))
+    }
+
+    /// Merge options passed after the subcommand, which take priority.
+    pub fn merge(&mut self, other: Common) {
+        self.manifest_path = other.manifest_path.or_else(|| self.manifest_path.take());
+        self.target_dir = other.target_dir.or_else(|| self.target_dir.take());
+        self.sysroot_dir = other.sysroot_dir.or_else(|| self.sysroot_dir.take());
+        self.target.extend(other.target);
+        self.crates = other.crates.or(self.crates);
+        self.features.extend(other.features);
+        self.no_default_features |= other.no_default_features;
+        self.std_features.extend(other.std_features);
+        self.extra_crates.extend(other.extra_crates);
+        self.profile = other.profile.or_else(|| self.profile.take());
+        self.offline |= other.offline;
+        self.frozen |= other.frozen;
+        self.lockfile = other.lockfile.or_else(|| self.lockfile.take());
+        self.locked |= other.locked;
+        self.vendor_dir = other.vendor_dir.or_else(|| self.vendor_dir.take());
+        self.local_registry = other.local_registry.or_else(|| self.local_registry.take());
+        self.rust_src_dir = other.rust_src_dir.or_else(|| self.rust_src_dir.take());
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Remove the built sysroot.
+    ///
+    /// By default this removes everything.
+    /// Pass `--target` to only remove the sysroot for that target.
+    Clean(CleanOpts),
+
+    /// Print information about the sysroot, without building it.
+    ///
+    /// Paths are absolute, and resolved the same way as when building.
+    Print(PrintOpts),
+
+    /// Build the sysroot, and run a command that uses it.
+    ///
+    /// Instead of writing `.cargo/config.toml`, the command is run with
+    /// `CARGO_BUILD_TARGET` and `CARGO_ENCODED_RUSTFLAGS` set to use the
+    /// sysroot.
+    ///
+    /// Exactly one target must be configured.
+    ///
+    /// Example: `cargo sysroot exec -- cargo build`
+    Exec(ExecOpts),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct CleanOpts {
+    #[structopt(flatten)]
+    pub common: Common,
+
+    /// Only remove the intermediate build directory.
+    #[structopt(long)]
+    pub build_dir: bool,
+
+    /// Only remove the host tools copied into the sysroot.
+    #[structopt(long)]
+    pub host_tools: bool,
+
+    /// List what would be removed, without removing anything.
+    #[structopt(long)]
+    pub dry_run: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct ExecOpts {
+    #[structopt(flatten)]
+    pub common: Common,
+
+    /// Rebuild the sysroot, even if it's up to date.
+    #[structopt(long)]
+    pub force: bool,
+
+    /// The command to run, and its arguments.
+    #[structopt(required = true, last = true, parse(from_os_str))]
+    pub command: Vec<OsString>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct PrintOpts {
+    #[structopt(flatten)]
+    pub common: Common,
+
+    /// What to print.
+    ///
+    /// `sysroot` prints the sysroot directory, `target-libdir` the
+    /// directory containing the sysroot crates for every target,
+    /// `rustflags` the flags to pass to rustc to use the sysroot,
+    /// `profile` the `[profile]` table the sysroot crates are built with,
+    /// and `all` everything.
+    #[structopt(possible_values = &["sysroot", "target-libdir", "rustflags", "profile", "all"])]
+    pub what: Print,
+
+    /// Output format.
+    #[structopt(long, default_value = "text", possible_values = &["text", "json"])]
+    pub format: Format,
+}
+
+/// What to print with `cargo sysroot print`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Print {
+    Sysroot,
+    TargetLibdir,
+    Rustflags,
+    Profile,
+    All,
+}
+
+impl FromStr for Print {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sysroot" => Ok(Print::Sysroot),
+            "target-libdir" => Ok(Print::TargetLibdir),
+            "rustflags" => Ok(Print::Rustflags),
+            "profile" => Ok(Print::Profile),
+            "all" => Ok(Print::All),
+            s => Err(anyhow!("Unknown print request: {}", s)),
+        }
+    }
+}
+
+/// Output format for `cargo sysroot print`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
 
-    /// The [profile] section from `Cargo.toml`.
-    /// Some use-cases require the sysroot crates be built with this matching.
-    #[structopt(skip)]
-    pub cargo_profile: Option<Profile>,
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            s => Err(anyhow!("Unknown format: {}", s)),
+        }
+    }
 }
 
 #[derive(StructOpt, Debug)]
@@ -0,0 +1,441 @@
+//! Creating and updating `.cargo/config.toml`.
+use crate::{target_name, util};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fmt,
+    fs,
+    iter::Peekable,
+    path::{Path, PathBuf},
+};
+use toml_edit::{value, Array, DocumentMut, Item, Table, Value};
+
+/// A key in the cargo configuration that was changed by
+/// [`update_cargo_config`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigChange {
+    /// The key did not exist, and was added.
+    Added(String),
+
+    /// The key existed with a different value, and was updated.
+    Updated(String),
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigChange::Added(key) => write!(f, "added `{}`", key),
+            ConfigChange::Updated(key) => write!(f, "updated `{}`", key),
+        }
+    }
+}
+
+/// Find the cargo configuration file in `.cargo`.
+///
+/// Cargo prefers `config.toml`, but will still read the legacy `config`
+/// if that's all there is, so we update that instead of creating a second
+/// one.
+pub fn cargo_config_path() -> PathBuf {
+    let cargo = Path::new(".cargo");
+    let legacy = cargo.join("config");
+    let config = cargo.join("config.toml");
+    if !config.exists() && legacy.is_file() {
+        legacy
+    } else {
+        config
+    }
+}
+
+/// Create or update the `.cargo/config.toml` at `config` to use `targets`
+/// and `sysroot`.
+///
+/// Only `build.target`, and the `--sysroot` flag in `rustflags`, are owned
+/// by cargo-sysroot. If there are multiple `targets`, `build.target` is
+/// written as a list. Everything else in an existing configuration,
+/// including comments and formatting, is left alone.
+///
+/// Cargo ignores `build.rustflags` for a target if any `[target.<triple>]`
+/// or `[target.'cfg(..)']` table for it sets `rustflags`, so the flag is
+/// added to that table instead, preferring `[target.<triple>]`. Any stale
+/// `--sysroot` in the other tables for the target is removed.
+/// `cfg(..)` tables are matched using `rustc --print cfg`.
+///
+/// Returns the keys that were changed, if any. The file is only written if
+/// something changed.
+///
+/// # Errors
+///
+/// - If `config` couldn't be read, parsed, or written.
+/// - If a `rustflags` is not a string or an array of strings.
+/// - If one table's `--sysroot` would apply to a target that another table has
+///   to set it for, since rustc only accepts one.
+pub fn update_cargo_config(
+    config: &Path,
+    targets: &[PathBuf],
+    sysroot: &Path,
+) -> Result<Vec<ConfigChange>> {
+    let names = targets
+        .iter()
+        .map(|t| {
+            t.to_str()
+                .context("Failed to convert target.json path to utf-8")
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let sysroot_dir = sysroot
+        .canonicalize()
+        .with_context(|| {
+            format!(
+                "Couldn't get canonical path to sysroot: {}",
+                sysroot.display()
+            )
+        })?
+        .to_str()
+        .with_context(|| {
+            format!(
+                "Failed to convert sysroot path to utf-8: {}",
+                sysroot.display()
+            )
+        })?
+        .to_string();
+
+    let mut doc = match fs::read_to_string(config) {
+        Ok(s) => s
+            .parse::<DocumentMut>()
+            .with_context(|| format!("Couldn't parse {}", config.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => DocumentMut::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Couldn't read {}", config.display()));
+        }
+    };
+    let target_tables = target_tables(&doc, targets)?;
+    let build = doc
+        .entry("build")
+        .or_insert_with(|| Item::Table(Table::new()))
+        .as_table_like_mut()
+        .context("`build` in the cargo configuration was not a table")?;
+    let mut changes = Vec::new();
+
+    let target = match names.as_slice() {
+        [target] => Value::from(*target),
+        names => Value::from(names.iter().copied().collect::<Array>()),
+    };
+    match build.get_mut("target") {
+        Some(item) => {
+            let old = match item.as_value() {
+                Some(Value::String(s)) => Some(vec![s.value().as_str()]),
+                Some(Value::Array(a)) => a.iter().map(Value::as_str).collect(),
+                _ => None,
+            };
+            if old.as_ref() != Some(&names) {
+                set_value(item, target);
+                changes.push(ConfigChange::Updated("build.target".into()));
+            }
+        }
+        None => {
+            build.insert("target", Item::Value(target));
+            changes.push(ConfigChange::Added("build.target".into()));
+        }
+    }
+
+    // Still used for any target without its own `rustflags`.
+    match build.get_mut("rustflags") {
+        Some(item) => {
+            if update_rustflags(item, "build.rustflags", Some(&sysroot_dir))? {
+                changes.push(ConfigChange::Updated("build.rustflags".into()));
+            }
+        }
+        None => {
+            build.insert(
+                "rustflags",
+                value(
+                    ["--sysroot", sysroot_dir.as_str()]
+                        .iter()
+                        .copied()
+                        .collect::<Array>(),
+                ),
+            );
+            changes.push(ConfigChange::Added("build.rustflags".into()));
+        }
+    }
+
+    for (key, owned) in target_tables {
+        let name = if key.contains(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_') {
+            format!("target.'{}'.rustflags", key)
+        } else {
+            format!("target.{}.rustflags", key)
+        };
+        let item = doc["target"][key.as_str()].get_mut("rustflags").unwrap();
+        let sysroot = if owned {
+            Some(sysroot_dir.as_str())
+        } else {
+            None
+        };
+        if update_rustflags(item, &name, sysroot)? {
+            changes.push(ConfigChange::Updated(name));
+        }
+    }
+
+    if !changes.is_empty() {
+        if let Some(parent) = config.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(config, doc.to_string())
+            .with_context(|| format!("Failed writing {}", config.display()))?;
+    }
+    Ok(changes)
+}
+
+/// The `[target]` tables in `doc` with `rustflags` that apply to any of
+/// `targets`, and whether each should have the `--sysroot` flag.
+///
+/// Only one table gets it for each target, the others must not have it.
+fn target_tables(doc: &DocumentMut, targets: &[PathBuf]) -> Result<Vec<(String, bool)>> {
+    let tables = match doc.get("target").and_then(Item::as_table_like) {
+        Some(tables) => tables,
+        None => return Ok(Vec::new()),
+    };
+    let mut matching = Vec::with_capacity(targets.len());
+    for target in targets {
+        let name = target_name(target)?
+            .to_str()
+            .context("Target name was not valid utf-8")?;
+        let mut cfg = None;
+        let mut keys = Vec::new();
+        for (key, table) in tables.iter() {
+            if table.get("rustflags").is_none() {
+                continue;
+            }
+            let applies = match key.strip_prefix("cfg(").and_then(|k| k.strip_suffix(')')) {
+                Some(expr) => {
+                    if cfg.is_none() {
+                        cfg = Some(util::get_target_cfg(target).with_context(|| {
+                            format!("Couldn't get the cfg of {}", target.display())
+                        })?);
+                    }
+                    cfg_matches(expr, cfg.as_ref().unwrap())
+                        .with_context(|| format!("Couldn't parse `[target.'{}']`", key))?
+                }
+                None => key == name,
+            };
+            if applies {
+                keys.push(key.to_string());
+            }
+        }
+        // Cargo uses all of them, so the exact one is as good as any.
+        keys.sort_by_key(|k| k != name);
+        matching.push((target, keys));
+    }
+
+    let owned: Vec<&String> = matching.iter().filter_map(|(_, k)| k.first()).collect();
+    let mut tables: Vec<(String, bool)> = Vec::new();
+    for (target, keys) in &matching {
+        let mut both = keys.iter().filter(|k| owned.contains(k));
+        if let (Some(a), Some(b)) = (both.next(), both.next()) {
+            return Err(anyhow!(
+                "Both `[target.'{}']` and `[target.'{}']` set `rustflags` for {}, so only one \
+                 can have `--sysroot`, but they're also used for other targets. Build one \
+                 target at a time, or pass `--no-config` and set `--sysroot` yourself",
+                a,
+                b,
+                target.display()
+            ));
+        }
+        for key in keys {
+            if !tables.iter().any(|(k, _)| k == key) {
+                tables.push((key.clone(), owned.contains(&key)));
+            }
+        }
+    }
+    Ok(tables)
+}
+
+/// Set or remove the `--sysroot` flag in the `rustflags` `item`, named
+/// `key`.
+///
+/// Returns whether anything changed.
+fn update_rustflags(item: &mut Item, key: &str, sysroot: Option<&str>) -> Result<bool> {
+    let old = match item.as_value() {
+        Some(Value::String(s)) => s.value().split_whitespace().map(Into::into).collect(),
+        Some(Value::Array(a)) => a
+            .iter()
+            .map(|v| v.as_str().map(Into::into))
+            .collect::<Option<Vec<String>>>()
+            .with_context(|| format!("`{}` contained a non-string value", key))?,
+        _ => return Err(anyhow!("`{}` was not a string or an array of strings", key)),
+    };
+    let new = with_sysroot_flag(&old, sysroot);
+    if new == old {
+        return Ok(false);
+    }
+    match item.as_value_mut() {
+        Some(Value::Array(a)) => {
+            // Edit in place so the other flags keep their formatting.
+            replace_sysroot_flag(a, sysroot);
+        }
+        _ => set_value(item, new.join(" ").into()),
+    }
+    Ok(true)
+}
+
+/// Whether the `cfg(..)` predicate `expr` holds for a target with the
+/// `rustc --print cfg` output `cfg`.
+fn cfg_matches(expr: &str, cfg: &[String]) -> Result<bool> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '(' | ')' | ',' | '=' => tokens.push(c.to_string()),
+            '"' => {
+                let mut s = String::from('"');
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => return Err(anyhow!("Unterminated string")),
+                    }
+                }
+                s.push('"');
+                tokens.push(s);
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut s = String::from(c);
+                while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                    s.push(c);
+                    chars.next();
+                }
+                tokens.push(s);
+            }
+            c => return Err(anyhow!("Unexpected `{}`", c)),
+        }
+    }
+    let mut tokens = tokens.iter().map(String::as_str).peekable();
+    let matches = cfg_predicate(&mut tokens, cfg)?;
+    match tokens.next() {
+        None => Ok(matches),
+        Some(t) => Err(anyhow!("Unexpected `{}`", t)),
+    }
+}
+
+/// Evaluate one predicate from `tokens`, see [`cfg_matches`].
+fn cfg_predicate<'a, I>(tokens: &mut Peekable<I>, cfg: &[String]) -> Result<bool>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let name = match tokens.next() {
+        Some(t) if !t.starts_with('"') && !"(),=".contains(t) => t,
+        t => return Err(anyhow!("Expected a cfg name, found `{}`", t.unwrap_or(""))),
+    };
+    match tokens.peek() {
+        Some(&"(") => {
+            tokens.next();
+            let mut values = Vec::new();
+            while tokens.peek() != Some(&")") {
+                values.push(cfg_predicate(tokens, cfg)?);
+                if tokens.peek() == Some(&",") {
+                    tokens.next();
+                } else {
+                    break;
+                }
+            }
+            if tokens.next() != Some(")") {
+                return Err(anyhow!("Expected `)` after `{}(`", name));
+            }
+            match (name, values.as_slice()) {
+                ("all", _) => Ok(values.iter().all(|v| *v)),
+                ("any", _) => Ok(values.iter().any(|v| *v)),
+                ("not", [value]) => Ok(!value),
+                _ => Err(anyhow!("Unknown cfg operator `{}`", name)),
+            }
+        }
+        Some(&"=") => {
+            tokens.next();
+            match tokens.next() {
+                Some(value) if value.starts_with('"') => {
+                    Ok(cfg.iter().any(|c| *c == format!("{}={}", name, value)))
+                }
+                _ => Err(anyhow!("Expected a string after `{} =`", name)),
+            }
+        }
+        _ => Ok(cfg.iter().any(|c| c == name)),
+    }
+}
+
+/// Replace the value of `item`, keeping any surrounding comments/whitespace.
+fn set_value(item: &mut Item, mut new: Value) {
+    if let Some(old) = item.as_value() {
+        *new.decor_mut() = old.decor().clone();
+    }
+    *item = Item::Value(new);
+}
+
+/// `flags`, with any existing `--sysroot` replaced by `sysroot`, or removed.
+fn with_sysroot_flag(flags: &[String], sysroot: Option<&str>) -> Vec<String> {
+    let mut new = Vec::with_capacity(flags.len() + 2);
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        if flag == "--sysroot" {
+            flags.next();
+        } else if !flag.starts_with("--sysroot=") {
+            new.push(flag.clone());
+        }
+    }
+    if let Some(sysroot) = sysroot {
+        new.push("--sysroot".into());
+        new.push(sysroot.into());
+    }
+    new
+}
+
+/// Replace any `--sysroot` flags, and their values, in `flags`, or remove
+/// them if `sysroot` is `None`.
+///
+/// Comments attached to removed flags are kept, and the new flags follow the
+/// layout of the first flag in the array.
+fn replace_sysroot_flag(flags: &mut Array, sysroot: Option<&str>) {
+    let mut removed_prefix = String::new();
+    let mut remove = |flags: &mut Array, i: usize| {
+        let old = flags.remove(i);
+        if let Some(prefix) = old.decor().prefix().and_then(|p| p.as_str()) {
+            if prefix.contains('#') {
+                removed_prefix.push_str(prefix.trim_end());
+            }
+        }
+    };
+    let mut i = 0;
+    while i < flags.len() {
+        match flags.get(i).and_then(Value::as_str) {
+            Some("--sysroot") => {
+                remove(flags, i);
+                if i < flags.len() {
+                    remove(flags, i);
+                }
+            }
+            Some(s) if s.starts_with("--sysroot=") => remove(flags, i),
+            _ => i += 1,
+        }
+    }
+    let sysroot = match sysroot {
+        Some(sysroot) => sysroot,
+        None => return,
+    };
+    let prefix = flags
+        .get(0)
+        .and_then(|v| v.decor().prefix())
+        .and_then(|p| p.as_str())
+        .filter(|p| p.contains('\n'))
+        .map(|p| p.trim_start_matches(|c| c != '\n').to_string());
+    for (i, flag) in ["--sysroot", sysroot].iter().enumerate() {
+        let mut flag = Value::from(*flag);
+        match (&prefix, i) {
+            (Some(prefix), 0) => flag.decor_mut().set_prefix(removed_prefix.clone() + prefix),
+            (Some(prefix), _) => flag.decor_mut().set_prefix(prefix.as_str()),
+            (None, 0) if !removed_prefix.is_empty() => {
+                flag.decor_mut().set_prefix(removed_prefix.clone())
+            }
+            _ => flag
+                .decor_mut()
+                .set_prefix(if flags.is_empty() { "" } else { " " }),
+        }
+        flags.push_formatted(flag);
+    }
+}
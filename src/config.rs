@@ -0,0 +1,554 @@
+//! The `package.metadata.cargo-sysroot` configuration.
+use crate::{
+    is_target_spec,
+    util,
+    CToolchain,
+    CrateOverride,
+    CrateSettings,
+    Features,
+    StdFeature,
+    Sysroot,
+    SysrootBuilder,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize,
+    Deserializer,
+};
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// Configuration from the `package.metadata.cargo-sysroot` table
+/// of a `Cargo.toml`.
+///
+/// Every key is optional, and unknown keys are an error.
+///
+/// Relative paths are relative to the `Cargo.toml` they came from,
+/// when loaded with [`SysrootConfig::from_manifest`].
+///
+/// The same keys can be used in `workspace.metadata.cargo-sysroot`,
+/// as defaults for every workspace member.
+/// See [`SysrootConfig::from_workspace`].
+///
+/// ```toml
+/// [package.metadata.cargo-sysroot]
+/// target = "my_custom_target.json" # Or a list of targets
+/// crates = "alloc"
+/// features = ["mem"]
+/// default-features = true
+/// std-features = ["panic-unwind"]
+/// extra-crates = ["panic_abort"]
+/// rustflags = ["-Cdebuginfo=2"]
+/// rust-src = "path/to/rust/library"
+/// output = "target/sysroot"
+/// lockfile = "sysroot.lock"
+///
+/// [package.metadata.cargo-sysroot.overrides]
+/// compiler_builtins = { path = "../compiler-builtins/compiler-builtins" }
+///
+/// [package.metadata.cargo-sysroot.c-toolchain.x86_64-unknown-none]
+/// cc = "clang"
+/// ar = "llvm-ar"
+/// cflags = ["-ffreestanding"]
+///
+/// [package.metadata.cargo-sysroot.crate-settings.compiler_builtins]
+/// opt-level = 3
+/// codegen-units = 1
+///
+/// [package.metadata.cargo-sysroot.crate-settings.alloc]
+/// rustflags = ["--cfg", "my_cfg"]
+///
+/// [package.metadata.cargo-sysroot.profile.release]
+/// lto = false
+/// opt-level = 2
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SysrootConfig {
+    /// Targets to build for, either a single string or a list.
+    ///
+    /// See [`crate::SysrootBuilder::target`].
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub target: Vec<PathBuf>,
+
+    /// Which crates to build.
+    ///
+    /// See [`Sysroot`]'s [`FromStr`][std::str::FromStr] implementation for
+    /// the names.
+    pub crates: Option<Sysroot>,
+
+    /// Features to enable, in addition to the defaults.
+    ///
+    /// See [`Features`]'s [`FromStr`][std::str::FromStr] implementation for
+    /// the names.
+    #[serde(default)]
+    pub features: Vec<Features>,
+
+    /// Whether to enable the default [`Features::CompilerBuiltinsMem`].
+    ///
+    /// `true` if not set.
+    pub default_features: Option<bool>,
+
+    /// `std` features to enable.
+    ///
+    /// See [`StdFeature`]'s [`FromStr`][std::str::FromStr] implementation
+    /// for the names.
+    #[serde(default)]
+    pub std_features: Vec<StdFeature>,
+
+    /// Library crates to build, in addition to [`SysrootConfig::crates`].
+    ///
+    /// See [`crate::SysrootBuilder::extra_crates`].
+    #[serde(default)]
+    pub extra_crates: Vec<String>,
+
+    /// Custom flags to pass to `rustc`.
+    ///
+    /// See [`crate::SysrootBuilder::rustc_flags`].
+    #[serde(default)]
+    pub rustflags: Vec<String>,
+
+    /// The rust sources to use.
+    ///
+    /// See [`crate::SysrootBuilder::rust_src`].
+    pub rust_src: Option<PathBuf>,
+
+    /// Where to place the sysroot.
+    ///
+    /// See [`crate::SysrootBuilder::output`].
+    pub output: Option<PathBuf>,
+
+    /// `Cargo.lock` to build the sysroot with.
+    ///
+    /// See [`crate::SysrootBuilder::lockfile`].
+    pub lockfile: Option<PathBuf>,
+
+    /// Where to get crates from instead, by name.
+    ///
+    /// See [`crate::SysrootBuilder::override_crate`].
+    #[serde(default)]
+    pub overrides: BTreeMap<String, CrateOverride>,
+
+    /// C toolchains, by target name.
+    ///
+    /// See [`crate::SysrootBuilder::c_toolchain`].
+    #[serde(default)]
+    pub c_toolchain: BTreeMap<String, CToolchain>,
+
+    /// Settings for single crates, by name.
+    ///
+    /// See [`crate::SysrootBuilder::crate_settings`].
+    #[serde(default)]
+    pub crate_settings: BTreeMap<String, CrateSettings>,
+
+    /// Profile settings merged over the profiles from the manifest.
+    ///
+    /// See [`crate::SysrootBuilder::profile_overrides`].
+    #[serde(default)]
+    pub profile: toml::value::Table,
+}
+
+impl SysrootConfig {
+    /// Load the `package.metadata.cargo-sysroot` table from the `Cargo.toml`
+    /// at `manifest`.
+    ///
+    /// A missing table is the same as an empty one.
+    ///
+    /// Relative paths are made relative to the directory of `manifest`.
+    ///
+    /// # Errors
+    ///
+    /// - If `manifest` could not be read or parsed.
+    /// - If the table contains unknown keys, or keys with invalid values.
+    pub fn from_manifest(manifest: &Path) -> Result<Self> {
+        let config = read_manifest(manifest)?
+            .package
+            .and_then(|p| p.metadata)
+            .and_then(|m| m.cargo_sysroot)
+            .unwrap_or_default();
+        let root = manifest.parent().unwrap_or_else(|| Path::new(""));
+        Ok(config.relative_to(root))
+    }
+
+    /// Load the `workspace.metadata.cargo-sysroot` table from the `Cargo.toml`
+    /// at `manifest`.
+    ///
+    /// See [`SysrootConfig::from_manifest`] for details.
+    pub fn from_workspace_manifest(manifest: &Path) -> Result<Self> {
+        let config = read_manifest(manifest)?
+            .workspace
+            .and_then(|p| p.metadata)
+            .and_then(|m| m.cargo_sysroot)
+            .unwrap_or_default();
+        let root = manifest.parent().unwrap_or_else(|| Path::new(""));
+        Ok(config.relative_to(root))
+    }
+
+    /// Load the configuration for the workspace `manifest` is part of.
+    ///
+    /// If `manifest` is the workspace root, this includes every workspace
+    /// member, otherwise just the package at `manifest`.
+    ///
+    /// Each members `package.metadata.cargo-sysroot` is layered over the
+    /// `workspace.metadata.cargo-sysroot` defaults,
+    /// see [`SysrootConfig::with_defaults`].
+    ///
+    /// A package that isn't part of a workspace is its own workspace root.
+    ///
+    /// # Errors
+    ///
+    /// - If `cargo metadata` fails.
+    /// - If any of the configurations could not be loaded.
+    pub fn from_workspace(manifest: &Path) -> Result<WorkspaceConfig> {
+        let metadata = cargo_metadata(manifest)?;
+        let root_manifest = metadata.workspace_root.join("Cargo.toml");
+        let defaults = Self::from_workspace_manifest(&root_manifest)?;
+
+        let manifest = manifest
+            .canonicalize()
+            .with_context(|| format!("Couldn't find {}", manifest.display()))?;
+        let workspace_members = metadata.workspace_members;
+        let members = metadata
+            .packages
+            .into_iter()
+            .filter(|p| workspace_members.contains(&p.id))
+            .filter(|p| manifest == root_manifest || manifest == p.manifest_path)
+            .map(|p| {
+                let config = Self::from_manifest(&p.manifest_path)?.with_defaults(&defaults);
+                Ok((p.name, config))
+            })
+            .collect::<Result<_>>()?;
+        Ok(WorkspaceConfig {
+            root_manifest,
+            members,
+        })
+    }
+
+    /// Use the values from `defaults` for anything not set in `self`.
+    pub fn with_defaults(mut self, defaults: &Self) -> Self {
+        if self.target.is_empty() {
+            self.target = defaults.target.clone();
+        }
+        self.crates = self.crates.or(defaults.crates);
+        if self.features.is_empty() {
+            self.features = defaults.features.clone();
+        }
+        self.default_features = self.default_features.or(defaults.default_features);
+        if self.std_features.is_empty() {
+            self.std_features = defaults.std_features.clone();
+        }
+        if self.extra_crates.is_empty() {
+            self.extra_crates = defaults.extra_crates.clone();
+        }
+        if self.rustflags.is_empty() {
+            self.rustflags = defaults.rustflags.clone();
+        }
+        if self.rust_src.is_none() {
+            self.rust_src = defaults.rust_src.clone();
+        }
+        if self.output.is_none() {
+            self.output = defaults.output.clone();
+        }
+        if self.lockfile.is_none() {
+            self.lockfile = defaults.lockfile.clone();
+        }
+        for (name, source) in &defaults.overrides {
+            self.overrides
+                .entry(name.clone())
+                .or_insert_with(|| source.clone());
+        }
+        for (target, toolchain) in &defaults.c_toolchain {
+            self.c_toolchain
+                .entry(target.clone())
+                .or_insert_with(|| toolchain.clone());
+        }
+        for (name, settings) in &defaults.crate_settings {
+            self.crate_settings
+                .entry(name.clone())
+                .or_insert_with(|| settings.clone());
+        }
+        let mut profile = defaults.profile.clone();
+        util::merge_toml(&mut profile, self.profile);
+        self.profile = profile;
+        self
+    }
+
+    /// Make relative paths relative to `root`.
+    ///
+    /// Targets are only changed if they look like
+    /// JSON Target Specifications, not target triples.
+    fn relative_to(mut self, root: &Path) -> Self {
+        for target in &mut self.target {
+            if is_target_spec(target) {
+                *target = root.join(&target);
+            }
+        }
+        self.rust_src = self.rust_src.map(|p| root.join(p));
+        self.output = self.output.map(|p| root.join(p));
+        self.lockfile = self.lockfile.map(|p| root.join(p));
+        for source in self.overrides.values_mut() {
+            if let CrateOverride::Path { path } = source {
+                *path = root.join(&path);
+            }
+        }
+        self
+    }
+
+    /// The [`Features`] to enable, including the defaults.
+    pub fn all_features(&self) -> Vec<Features> {
+        let mut features = self.features.clone();
+        if self.default_features.unwrap_or(true) {
+            features.push(Features::CompilerBuiltinsMem);
+        }
+        features
+    }
+}
+
+/// The sysroot configuration of a workspace.
+///
+/// See [`SysrootConfig::from_workspace`].
+#[derive(Debug, Clone)]
+pub struct WorkspaceConfig {
+    /// The `Cargo.toml` at the root of the workspace.
+    ///
+    /// Cargo only uses the profiles from here.
+    pub root_manifest: PathBuf,
+
+    /// The configuration for each member, by package name.
+    pub members: Vec<(String, SysrootConfig)>,
+}
+
+impl WorkspaceConfig {
+    /// A [`SysrootBuilder`] for every distinct sysroot the members need.
+    ///
+    /// Members that need the same configuration share a builder,
+    /// with all of their targets.
+    /// Members without any targets don't need a sysroot, and are skipped.
+    ///
+    /// # Errors
+    ///
+    /// - If members need different configurations for the same target in the
+    ///   same sysroot.
+    pub fn builders(&self) -> Result<Vec<SysrootBuilder>> {
+        // Configurations without targets, and who needs them.
+        let mut groups: Vec<(SysrootConfig, Vec<PathBuf>, Vec<&str>)> = Vec::new();
+        for (name, config) in &self.members {
+            let key = SysrootConfig {
+                target: Vec::new(),
+                ..config.clone()
+            };
+            for target in &config.target {
+                let conflict = groups.iter().find(|(other, targets, _)| {
+                    *other != key && other.output == key.output && targets.contains(target)
+                });
+                if let Some((_, _, others)) = conflict {
+                    return Err(anyhow!(
+                        "Package `{}` needs a different sysroot for `{}` than `{}`",
+                        name,
+                        target.display(),
+                        others.join("`, `")
+                    ));
+                }
+            }
+            match groups.iter_mut().find(|(other, ..)| *other == key) {
+                Some((_, targets, names)) => {
+                    for target in &config.target {
+                        if !targets.contains(target) {
+                            targets.push(target.clone());
+                        }
+                    }
+                    names.push(name);
+                }
+                None => groups.push((key, config.target.clone(), vec![name])),
+            }
+        }
+        Ok(groups
+            .into_iter()
+            .filter(|(_, targets, _)| !targets.is_empty())
+            .map(|(config, target, _)| {
+                SysrootBuilder::from_config(
+                    &self.root_manifest,
+                    &SysrootConfig { target, ..config },
+                )
+            })
+            .collect())
+    }
+}
+
+/// Just enough of a `Cargo.toml` to find our configuration.
+///
+/// Parsed directly from the manifest, so errors point at the right line.
+#[derive(Deserialize)]
+struct Manifest {
+    package: Option<Package>,
+    workspace: Option<Package>,
+}
+
+/// Either the `package` or `workspace` table.
+#[derive(Deserialize)]
+struct Package {
+    metadata: Option<Metadata>,
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    #[serde(rename = "cargo-sysroot")]
+    cargo_sysroot: Option<SysrootConfig>,
+}
+
+/// Read just enough of the `Cargo.toml` at `manifest`.
+fn read_manifest(manifest: &Path) -> Result<Manifest> {
+    toml::from_str(
+        &fs::read_to_string(manifest)
+            .with_context(|| format!("Couldn't read {}", manifest.display()))?,
+    )
+    .with_context(|| {
+        format!(
+            "Couldn't load cargo-sysroot configuration from {}",
+            manifest.display()
+        )
+    })
+}
+
+/// The parts of `cargo metadata` output we need.
+#[derive(Deserialize)]
+struct CargoMetadata {
+    packages: Vec<MetadataPackage>,
+    workspace_members: Vec<String>,
+    workspace_root: PathBuf,
+}
+
+#[derive(Deserialize)]
+struct MetadataPackage {
+    id: String,
+    name: String,
+    manifest_path: PathBuf,
+}
+
+/// Run `cargo metadata` for the workspace containing `manifest`.
+fn cargo_metadata(manifest: &Path) -> Result<CargoMetadata> {
+    let out = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+        .arg("metadata")
+        .arg("--no-deps")
+        .arg("--format-version")
+        .arg("1")
+        .arg("--manifest-path")
+        .arg(manifest)
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Couldn't find/run cargo command")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "Couldn't get workspace metadata for {}",
+            manifest.display()
+        ));
+    }
+    serde_json::from_slice(&out.stdout).context("Couldn't parse `cargo metadata` output")
+}
+
+impl<'de> Deserialize<'de> for Sysroot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for Features {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for StdFeature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl<'de> Deserialize<'de> for CrateOverride {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// Every key of every variant, so typos are named in the error,
+        /// instead of matching no variant.
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Keys {
+            path: Option<PathBuf>,
+            git: Option<String>,
+            branch: Option<String>,
+            tag: Option<String>,
+            rev: Option<String>,
+        }
+
+        let keys = Keys::deserialize(deserializer)?;
+        let git_refs = [&keys.branch, &keys.tag, &keys.rev];
+        let git_refs = git_refs.iter().filter(|r| r.is_some()).count();
+        match keys {
+            Keys {
+                path: Some(_),
+                git: Some(_),
+                ..
+            } => Err(de::Error::custom("`path` and `git` can't both be set")),
+            Keys {
+                path: Some(path), ..
+            } if git_refs == 0 => Ok(CrateOverride::Path { path }),
+            Keys { path: Some(_), .. } => Err(de::Error::custom(
+                "`branch`, `tag`, and `rev` can only be used with `git`",
+            )),
+            Keys { git: Some(_), .. } if git_refs > 1 => Err(de::Error::custom(
+                "only one of `branch`, `tag`, or `rev` can be set",
+            )),
+            Keys {
+                git: Some(git),
+                branch,
+                tag,
+                rev,
+                ..
+            } => Ok(CrateOverride::Git {
+                git,
+                branch,
+                tag,
+                rev,
+            }),
+            Keys { .. } => Err(de::Error::custom("expected `path` or `git`")),
+        }
+    }
+}
+
+/// Deserialize either a single path, or a list of them.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+    struct OneOrMany;
+
+    impl<'de> Visitor<'de> for OneOrMany {
+        type Value = Vec<PathBuf>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string or a list of strings")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(vec![v.into()])
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut v = Vec::new();
+            while let Some(p) = seq.next_element()? {
+                v.push(p);
+            }
+            Ok(v)
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany)
+}
@@ -0,0 +1,208 @@
+//! Up-to-date detection for built sysroots.
+use crate::{is_target_spec, target_name, util, CrateOverride, SysrootBuilder};
+use anyhow::{Context, Result};
+use std::{
+    collections::hash_map::DefaultHasher,
+    env,
+    fmt,
+    fs,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Everything that affects the contents of a targets sysroot.
+///
+/// Hashes are only compared against previous fingerprints,
+/// so a change in how they're computed just means a rebuild.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// Output of `rustc -vV`, version and commit hash.
+    rustc: String,
+
+    /// Hash of the file names, sizes, and modification times in `rust-src`.
+    rust_src: u64,
+
+    /// Hash of the target specification contents, or the builtin target name.
+    target: u64,
+
+    /// Which crates were built.
+    sysroot_crate: String,
+
+    /// Which extra library crates were built.
+    extra_crates: String,
+
+    /// Enabled [`crate::Features`].
+    features: String,
+
+    /// Enabled [`crate::StdFeature`]s.
+    std_features: String,
+
+    /// Custom rustc flags.
+    rustc_flags: String,
+
+    /// The `RUSTFLAGS` and `CARGO_ENCODED_RUSTFLAGS` environment variables,
+    /// which cargo also uses.
+    env_rustflags: String,
+
+    /// Settings for single crates.
+    crate_settings: String,
+
+    /// The C toolchain for the target.
+    c_toolchain: String,
+
+    /// Hash of the profiles, see [`SysrootBuilder::profiles`].
+    profile: u64,
+
+    /// The profile the crates were built with.
+    build_profile: String,
+
+    /// Hash of the lockfile, if not the one in `rust-src`.
+    lockfile: u64,
+
+    /// Hash of the crate overrides, and the contents of any paths.
+    overrides: u64,
+}
+
+impl Fingerprint {
+    /// Compute the fingerprint for building `target` with `builder`.
+    ///
+    /// `builder.rust_src` must be set.
+    pub fn new(builder: &SysrootBuilder, target: &Path) -> Result<Self> {
+        let rust_src = builder.rust_src.as_ref().unwrap();
+        let c_toolchain = format!("{:?}", builder.c_toolchain_env(target)?);
+        let rustc = util::get_rustc_version().context("Couldn't get rustc version")?;
+        let rust_src = {
+            let mut hasher = DefaultHasher::new();
+            hash_tree(rust_src, &mut hasher, &[])
+                .with_context(|| format!("Couldn't hash rust-src at {}", rust_src.display()))?;
+            hasher.finish()
+        };
+        let target = {
+            let mut hasher = DefaultHasher::new();
+            if is_target_spec(target) {
+                fs::read(target)
+                    .with_context(|| format!("Couldn't read target spec {}", target.display()))?
+                    .hash(&mut hasher);
+            } else {
+                target.hash(&mut hasher);
+            }
+            hasher.finish()
+        };
+        let profile = {
+            let mut hasher = DefaultHasher::new();
+            format!("{:?}", builder.profiles()?).hash(&mut hasher);
+            hasher.finish()
+        };
+        let lockfile = {
+            let mut hasher = DefaultHasher::new();
+            if let Some(lockfile) = &builder.lockfile {
+                fs::read(lockfile)
+                    .with_context(|| format!("Couldn't read lockfile {}", lockfile.display()))?
+                    .hash(&mut hasher);
+            }
+            hasher.finish()
+        };
+        let overrides = {
+            let mut hasher = DefaultHasher::new();
+            format!("{:?}", builder.overrides).hash(&mut hasher);
+            for source in builder.overrides.values() {
+                if let CrateOverride::Path { path } = source {
+                    // Building the crate itself doesn't change it.
+                    hash_tree(path, &mut hasher, &[".git", "target"])
+                        .with_context(|| format!("Couldn't hash override at {}", path.display()))?;
+                }
+            }
+            hasher.finish()
+        };
+        Ok(Self {
+            rustc: rustc.trim().into(),
+            rust_src,
+            target,
+            sysroot_crate: format!("{:?}", builder.sysroot_crate),
+            extra_crates: format!("{:?}", builder.extra_crates),
+            features: format!("{:?}", builder.features),
+            std_features: format!("{:?}", builder.std_features),
+            rustc_flags: format!("{:?}", builder.rustc_flags),
+            env_rustflags: format!(
+                "{:?}",
+                ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"].map(env::var_os)
+            ),
+            crate_settings: format!("{:?}", builder.crate_settings),
+            c_toolchain,
+            profile,
+            build_profile: builder.profile.clone(),
+            lockfile,
+            overrides,
+        })
+    }
+
+    /// Whether the sysroot for `target` in `sysroot_dir` was built with
+    /// this same fingerprint.
+    pub fn is_fresh(&self, sysroot_dir: &Path, target: &Path) -> Result<bool> {
+        match fs::read_to_string(fingerprint_path(sysroot_dir, target)?) {
+            Ok(old) => Ok(old == self.to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).context("Couldn't read sysroot fingerprint"),
+        }
+    }
+
+    /// Record this fingerprint for the sysroot for `target` in
+    /// `sysroot_dir`.
+    pub fn write(&self, sysroot_dir: &Path, target: &Path) -> Result<()> {
+        fs::write(fingerprint_path(sysroot_dir, target)?, self.to_string())
+            .context("Couldn't write sysroot fingerprint")
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.rustc)?;
+        writeln!(f, "rust-src: {:016x}", self.rust_src)?;
+        writeln!(f, "target: {:016x}", self.target)?;
+        writeln!(f, "crates: {}", self.sysroot_crate)?;
+        writeln!(f, "extra-crates: {}", self.extra_crates)?;
+        writeln!(f, "features: {}", self.features)?;
+        writeln!(f, "std-features: {}", self.std_features)?;
+        writeln!(f, "rustc-flags: {}", self.rustc_flags)?;
+        writeln!(f, "env-rustflags: {}", self.env_rustflags)?;
+        writeln!(f, "crate-settings: {}", self.crate_settings)?;
+        writeln!(f, "c-toolchain: {}", self.c_toolchain)?;
+        writeln!(f, "profile: {:016x}", self.profile)?;
+        writeln!(f, "build-profile: {}", self.build_profile)?;
+        writeln!(f, "lockfile: {:016x}", self.lockfile)?;
+        writeln!(f, "overrides: {:016x}", self.overrides)
+    }
+}
+
+/// Where the fingerprint for `target` is stored, next to its `lib` directory.
+fn fingerprint_path(sysroot_dir: &Path, target: &Path) -> Result<PathBuf> {
+    Ok(sysroot_dir
+        .join("lib")
+        .join("rustlib")
+        .join(target_name(target)?)
+        .join("cargo-sysroot.fingerprint"))
+}
+
+/// Hash the names, sizes, and modification times of everything in `dir`,
+/// except anything directly in it named in `skip`.
+fn hash_tree(dir: &Path, hasher: &mut DefaultHasher, skip: &[&str]) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.retain(|e| !skip.iter().any(|s| e.file_name() == *s));
+    // `read_dir` order is unspecified.
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let meta = entry.metadata()?;
+        entry.file_name().hash(hasher);
+        if meta.is_dir() {
+            hash_tree(&entry.path(), hasher, &[])?;
+        } else {
+            meta.len().hash(hasher);
+            meta.modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .hash(hasher);
+        }
+    }
+    Ok(())
+}
@@ -2,34 +2,49 @@
 //!
 //! Compiles the Rust sysroot crates, core, compiler_builtins, and alloc.
 use anyhow::{anyhow, Context, Error, Result};
-use cargo_toml2::{
-    from_path,
-    to_path,
-    CargoToml,
-    Dependency,
-    DependencyFull,
-    Package,
-    Patches,
-    TargetConfig,
-    Workspace,
-};
+use cargo_toml2::{CargoToml, Dependency, DependencyFull, Package, TargetConfig, Workspace};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::BTreeMap,
+    collections::{hash_map::DefaultHasher, BTreeMap},
     env,
-    ffi::OsString,
-    fs,
+    ffi::{OsStr, OsString},
+    fmt,
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, Stdio},
+    str::FromStr,
+    sync::{mpsc, Arc},
+    thread,
 };
 
+#[cfg(feature = "toml_edit")]
+mod cargo_config;
+mod config;
+mod fingerprint;
+mod library;
+mod observer;
+mod report;
 mod util;
+mod wrapper;
 
+#[cfg(feature = "toml_edit")]
+pub use cargo_config::{cargo_config_path, update_cargo_config, ConfigChange};
+pub use config::{SysrootConfig, WorkspaceConfig};
+use fingerprint::Fingerprint;
+use observer::Observer;
+pub use observer::{BuildEvent, BuildObserver, PrintObserver};
+use report::CargoMessage;
+pub use report::{BuildReport, CrateReport, TargetReport};
 pub use util::get_rust_src;
+pub use wrapper::wrap_rustc;
 
 /// The sysroot crates to build.
 ///
 /// See [`build_sysroot_with`] for details.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Sysroot {
     /// The core crate. Provides core functionality.
     ///
@@ -53,6 +68,174 @@ pub enum Sysroot {
     /// This implies [`Sysroot::Alloc`], [`Sysroot::Core`], and
     /// [`Sysroot::CompilerBuiltins`].
     Std,
+
+    /// The test crate, for the `cargo test` harness, and proc_macro.
+    ///
+    /// This implies [`Sysroot::Std`], and everything it does.
+    Test,
+}
+
+impl Sysroot {
+    /// Whether this includes [`Sysroot::Std`].
+    fn has_std(self) -> bool {
+        matches!(self, Sysroot::Std | Sysroot::Test)
+    }
+}
+
+impl FromStr for Sysroot {
+    type Err = Error;
+
+    /// Parse the name used on the command line and in
+    /// `package.metadata.cargo-sysroot`.
+    ///
+    /// One of `core`, `compiler-builtins`, `alloc`, `std`, or `test`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "core" => Ok(Sysroot::Core),
+            "compiler-builtins" | "compiler_builtins" => Ok(Sysroot::CompilerBuiltins),
+            "alloc" => Ok(Sysroot::Alloc),
+            "std" => Ok(Sysroot::Std),
+            "test" => Ok(Sysroot::Test),
+            _ => Err(anyhow!(
+                "Unknown sysroot crates `{}`, expected one of `core`, `compiler-builtins`, \
+                 `alloc`, `std`, or `test`",
+                s
+            )),
+        }
+    }
+}
+
+/// Where to get the sysroot crates dependencies from, instead of crates.io.
+///
+/// See [`SysrootBuilder::crate_source`] for usage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrateSource {
+    /// A directory of vendored crates, as created by `cargo vendor`.
+    Vendor(PathBuf),
+
+    /// A [local registry][1], a mirror of crates.io on disk.
+    ///
+    /// [1]: https://doc.rust-lang.org/cargo/reference/source-replacement.html#local-registry-sources
+    LocalRegistry(PathBuf),
+}
+
+/// The C toolchain to use for a target, for [`Features::CompilerBuiltinsC`].
+///
+/// Anything not set uses the `CC_<target>`, `AR_<target>`, and
+/// `CFLAGS_<target>` environment variables, or the defaults of the
+/// [`cc`][1] crate.
+///
+/// See [`SysrootBuilder::c_toolchain`] for usage.
+///
+/// [1]: https://docs.rs/cc
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct CToolchain {
+    /// The C compiler.
+    pub cc: Option<PathBuf>,
+
+    /// The archiver.
+    pub ar: Option<PathBuf>,
+
+    /// Extra flags to pass to the C compiler.
+    #[serde(default)]
+    pub cflags: Vec<String>,
+}
+
+/// Settings for just one sysroot crate.
+///
+/// See [`SysrootBuilder::crate_settings`] for usage.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CrateSettings {
+    /// Extra flags to pass to `rustc` for this crate, such as `--cfg`.
+    ///
+    /// These are passed through a rustc wrapper, see
+    /// [`SysrootBuilder::rustc_wrapper`].
+    #[serde(default)]
+    pub rustflags: Vec<String>,
+
+    /// [Profile settings][1] for this crate, such as `opt-level`, `debug`,
+    /// or `codegen-units`.
+    ///
+    /// [1]: https://doc.rust-lang.org/cargo/reference/profiles.html#overrides
+    #[serde(flatten)]
+    pub profile: toml::value::Table,
+}
+
+/// Where to get a sysroot crate from, instead of `rust-src` or crates.io.
+///
+/// In `package.metadata.cargo-sysroot.overrides` these are tables with either
+/// `path`, or `git` and at most one of `branch`, `tag`, or `rev`.
+///
+/// See [`SysrootBuilder::override_crate`] for usage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum CrateOverride {
+    /// A local directory, such as a git checkout of a fork.
+    Path {
+        /// The directory containing the crates `Cargo.toml`.
+        path: PathBuf,
+    },
+
+    /// A git repository, for crates that aren't in `rust-src`.
+    ///
+    /// At most one of `branch`, `tag`, or `rev` can be set.
+    /// The default branch is used if none are.
+    Git {
+        /// The repository URL.
+        git: String,
+
+        /// The branch to use.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        branch: Option<String>,
+
+        /// The tag to use.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tag: Option<String>,
+
+        /// The commit to use.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rev: Option<String>,
+    },
+}
+
+impl fmt::Display for CrateOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrateOverride::Path { path } => write!(f, "{}", path.display()),
+            CrateOverride::Git {
+                git,
+                branch,
+                tag,
+                rev,
+            } => match branch.as_ref().or(tag.as_ref()).or(rev.as_ref()) {
+                Some(reference) => write!(f, "{}#{}", git, reference),
+                None => write!(f, "{}", git),
+            },
+        }
+    }
+}
+
+/// What to remove with [`SysrootBuilder::clean`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Clean {
+    /// Everything, the whole sysroot and the build directory.
+    All,
+
+    /// The sysroot for just one target, `lib/rustlib/<target>`.
+    Target(PathBuf),
+
+    /// The intermediate build directory.
+    ///
+    /// See [`SysrootBuilder::target_dir`].
+    BuildDir,
+
+    /// The host tools copied into the sysroot.
+    ///
+    /// These aren't copied when the host is one of the targets,
+    /// so this is an error then.
+    HostTools,
 }
 
 /// Features to enable when building the sysroot crates
@@ -71,6 +254,11 @@ pub enum Features {
     /// optimized implementations, and fills in the rare unimplemented
     /// intrinsics.
     ///
+    /// This needs a C compiler for the target,
+    /// see [`SysrootBuilder::c_toolchain`].
+    /// Newer versions also need `RUST_COMPILER_RT_ROOT` set to the
+    /// `compiler-rt` sources.
+    ///
     /// [1]: https://github.com/rust-lang/compiler-builtins
     CompilerBuiltinsC,
 
@@ -78,14 +266,120 @@ pub enum Features {
     /// which disables any implementations which use
     /// inline assembly and fall back to pure Rust versions (if available).
     ///
+    /// This adds `compiler_builtins` as a dependency, so with
+    /// [`Sysroot::Core`] it's built too.
+    ///
+    /// Newer `rust-src` includes a `compiler_builtins` without this feature,
+    /// which can only disable its assembly without [`Sysroot::Alloc`]
+    /// or [`Sysroot::Std`], which always enable it.
+    ///
     /// [1]: https://github.com/rust-lang/compiler-builtins
-    // TODO: Would only work on the [`Sysroot::CompilerBuiltins`] target,
-    // as it's not exported through alloc, but could be forced by
-    // adding compiler_builtins as an explicit dependency and enabling it,
-    // relying on features collapsing.
     CompilerBuiltinsNoAsm,
 }
 
+impl FromStr for Features {
+    type Err = Error;
+
+    /// Parse the name used on the command line and in
+    /// `package.metadata.cargo-sysroot`.
+    ///
+    /// One of `mem`, `c`, or `no-asm`, the [`compiler_builtins`][1] feature
+    /// names.
+    ///
+    /// [1]: https://github.com/rust-lang/compiler-builtins
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mem" => Ok(Features::CompilerBuiltinsMem),
+            "c" => Ok(Features::CompilerBuiltinsC),
+            "no-asm" => Ok(Features::CompilerBuiltinsNoAsm),
+            _ => Err(anyhow!(
+                "Unknown feature `{}`, expected one of `mem`, `c`, or `no-asm`",
+                s
+            )),
+        }
+    }
+}
+
+/// Features of `std` to enable when building [`Sysroot::Std`].
+///
+/// Not every feature is available in every version of `rust-src`.
+///
+/// See [`SysrootBuilder::std_features`] for usage.
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Ord, Eq)]
+pub enum StdFeature {
+    /// Include the `panic_unwind` runtime, for targets with the `unwind`
+    /// panic strategy.
+    ///
+    /// Without this, only the `panic_abort` runtime is built.
+    PanicUnwind,
+
+    /// Symbolize backtraces, showing function names and line numbers.
+    Backtrace,
+
+    /// Capture backtraces, but don't symbolize them.
+    BacktraceTraceOnly,
+
+    /// Abort immediately on panic, without any formatting or unwinding.
+    ///
+    /// Only older `rust-src` has this, it's now `-Cpanic=immediate-abort`.
+    PanicImmediateAbort,
+
+    /// Choose implementations optimized for size over speed.
+    OptimizeForSize,
+
+    /// Use LLVM's libunwind, built from source, as the unwinder.
+    LlvmLibunwind,
+
+    /// Use the system's copy of LLVM's libunwind as the unwinder.
+    SystemLlvmLibunwind,
+
+    /// Make `RefCell` record where it was borrowed, for better borrow
+    /// errors.
+    DebugRefcell,
+}
+
+impl StdFeature {
+    /// The name of the feature in `std`'s `Cargo.toml`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            StdFeature::PanicUnwind => "panic-unwind",
+            StdFeature::Backtrace => "backtrace",
+            StdFeature::BacktraceTraceOnly => "backtrace-trace-only",
+            StdFeature::PanicImmediateAbort => "panic_immediate_abort",
+            StdFeature::OptimizeForSize => "optimize_for_size",
+            StdFeature::LlvmLibunwind => "llvm-libunwind",
+            StdFeature::SystemLlvmLibunwind => "system-llvm-libunwind",
+            StdFeature::DebugRefcell => "debug_refcell",
+        }
+    }
+}
+
+impl FromStr for StdFeature {
+    type Err = Error;
+
+    /// Parse the name used on the command line and in
+    /// `package.metadata.cargo-sysroot`.
+    ///
+    /// The same as `std`'s feature names, see [`StdFeature::name`].
+    fn from_str(s: &str) -> Result<Self> {
+        use StdFeature::*;
+        [
+            PanicUnwind,
+            Backtrace,
+            BacktraceTraceOnly,
+            PanicImmediateAbort,
+            OptimizeForSize,
+            LlvmLibunwind,
+            SystemLlvmLibunwind,
+            DebugRefcell,
+        ]
+        .iter()
+        .find(|f| f.name() == s)
+        .copied()
+        .ok_or_else(|| anyhow!("Unknown std feature `{}`", s))
+    }
+}
+
 /// A builder interface for constructing the Sysroot
 ///
 /// See the individual methods for more details on what this means
@@ -98,8 +392,11 @@ pub struct SysrootBuilder {
     /// Output directory, where the built sysroot will be anchored.
     output: PathBuf,
 
-    /// Target triple/json to build for
-    target: Option<PathBuf>,
+    /// Cargo target directory for building the sysroot crates.
+    target_dir: Option<PathBuf>,
+
+    /// Target triples/jsons to build for
+    targets: Vec<PathBuf>,
 
     /// The rust sources to use
     rust_src: Option<PathBuf>,
@@ -110,8 +407,53 @@ pub struct SysrootBuilder {
     /// What custom features to enable, if any. See [`Features`] for details.
     features: Vec<Features>,
 
+    /// Which `std` features to enable, if any.
+    std_features: Vec<StdFeature>,
+
+    /// Library crates to build, in addition to `sysroot_crate`.
+    extra_crates: Vec<String>,
+
+    /// Where to get crates from instead, by name.
+    overrides: BTreeMap<String, CrateOverride>,
+
     /// Custom flags to pass to rustc.
     rustc_flags: Vec<OsString>,
+
+    /// C toolchains, by target name.
+    c_toolchains: BTreeMap<String, CToolchain>,
+
+    /// Settings for single crates, by name.
+    crate_settings: BTreeMap<String, CrateSettings>,
+
+    /// Profile settings merged over those from `manifest`.
+    profile_overrides: toml::value::Table,
+
+    /// The rustc wrapper for [`CrateSettings::rustflags`].
+    rustc_wrapper: Option<PathBuf>,
+
+    /// Cargo profile to build with.
+    profile: String,
+
+    /// Whether to pass `--offline` to cargo.
+    offline: bool,
+
+    /// Whether to pass `--frozen` to cargo.
+    frozen: bool,
+
+    /// Where to get dependencies from, instead of crates.io.
+    crate_source: Option<CrateSource>,
+
+    /// `Cargo.lock` to start from, instead of the one in `rust_src`.
+    lockfile: Option<PathBuf>,
+
+    /// Whether to pass `--locked` to cargo.
+    locked: bool,
+
+    /// Whether to rebuild even if the sysroot is up to date.
+    force: bool,
+
+    /// Watches the build.
+    observer: Observer,
 }
 
 impl SysrootBuilder {
@@ -123,15 +465,99 @@ impl SysrootBuilder {
         Self {
             manifest: Default::default(),
             output: PathBuf::from(".").join("target").join("sysroot"),
-            target: Default::default(),
+            target_dir: Default::default(),
+            targets: Default::default(),
             // Set in [`SysrootBuilder::build`] since `new` can't error.
             rust_src: Default::default(),
             sysroot_crate,
             features: Vec::with_capacity(3),
+            std_features: Default::default(),
+            extra_crates: Default::default(),
+            overrides: Default::default(),
             rustc_flags: Default::default(),
+            c_toolchains: Default::default(),
+            crate_settings: Default::default(),
+            profile_overrides: Default::default(),
+            rustc_wrapper: None,
+            profile: "release".into(),
+            offline: false,
+            frozen: false,
+            crate_source: None,
+            lockfile: None,
+            locked: false,
+            force: false,
+            observer: Default::default(),
         }
     }
 
+    /// New [`SysrootBuilder`], configured from the
+    /// `package.metadata.cargo-sysroot` table in the `Cargo.toml` at
+    /// `manifest`.
+    ///
+    /// `manifest` is also used for [`SysrootBuilder::manifest`].
+    ///
+    /// See [`SysrootConfig`] for details.
+    ///
+    /// # Errors
+    ///
+    /// - If the configuration could not be loaded. See
+    ///   [`SysrootConfig::from_manifest`].
+    pub fn from_manifest(manifest: &Path) -> Result<Self> {
+        Ok(Self::from_config(
+            manifest,
+            &SysrootConfig::from_manifest(manifest)?,
+        ))
+    }
+
+    /// New [`SysrootBuilder`]s for every distinct sysroot the workspace
+    /// `manifest` is part of needs.
+    ///
+    /// See [`SysrootConfig::from_workspace`] and
+    /// [`WorkspaceConfig::builders`] for details.
+    ///
+    /// # Errors
+    ///
+    /// - If the configuration could not be loaded.
+    pub fn from_workspace(manifest: &Path) -> Result<Vec<Self>> {
+        SysrootConfig::from_workspace(manifest)?.builders()
+    }
+
+    /// New [`SysrootBuilder`], configured from `config`.
+    ///
+    /// `manifest` is used for [`SysrootBuilder::manifest`].
+    ///
+    /// Anything not set in `config` uses the defaults.
+    pub fn from_config(manifest: &Path, config: &SysrootConfig) -> Self {
+        let mut builder = Self::new(config.crates.unwrap_or(Sysroot::Alloc));
+        builder
+            .manifest(manifest.into())
+            .targets(&config.target)
+            .features(&config.all_features())
+            .std_features(&config.std_features)
+            .extra_crates(&config.extra_crates)
+            .rustc_flags(&config.rustflags);
+        if let Some(rust_src) = &config.rust_src {
+            builder.rust_src(rust_src.clone());
+        }
+        if let Some(output) = &config.output {
+            builder.output(output.clone());
+        }
+        if let Some(lockfile) = &config.lockfile {
+            builder.lockfile(lockfile.clone());
+        }
+        for (name, source) in &config.overrides {
+            builder.override_crate(name.as_str(), source.clone());
+        }
+        for (target, toolchain) in &config.c_toolchain {
+            builder.c_toolchain(target.as_str(), toolchain.clone());
+        }
+        for (name, settings) in &config.crate_settings {
+            builder.crate_settings(name.as_str(), settings.clone());
+        }
+        builder.profile_overrides(config.profile.clone());
+        builder
+    }
+
     /// Set path to the `Cargo.toml` of the project requiring a custom sysroot.
     ///
     /// If provided, any [Cargo Profile's][1] in the provided manifest
@@ -155,15 +581,183 @@ impl SysrootBuilder {
         self
     }
 
-    /// The target to compile *for*. This can be a target-triple,
+    /// Where the sysroot directory will be placed.
+    ///
+    /// See [`SysrootBuilder::output`].
+    pub fn output_dir(&self) -> &Path {
+        &self.output
+    }
+
+    /// Set the cargo target directory used to build the sysroot crates.
+    ///
+    /// This holds intermediate build artifacts, and can be placed anywhere,
+    /// such as a faster disk, or shared between builds.
+    /// Only the final artifacts are copied into the sysroot.
+    ///
+    /// By default this is `target` inside of [`SysrootBuilder::output`].
+    pub fn target_dir(&mut self, target_dir: PathBuf) -> &mut Self {
+        self.target_dir = Some(target_dir);
+        self
+    }
+
+    /// The cargo target directory used to build the sysroot crates.
+    ///
+    /// See [`SysrootBuilder::target_dir`].
+    pub fn build_dir(&self) -> PathBuf {
+        self.target_dir
+            .clone()
+            .unwrap_or_else(|| self.output.join("target"))
+    }
+
+    /// The absolute path to the sysroot directory, as returned by
+    /// [`SysrootBuilder::build`] in [`BuildReport::sysroot`].
+    ///
+    /// The sysroot doesn't have to be built yet.
+    ///
+    /// # Errors
+    ///
+    /// - If the current directory couldn't be determined.
+    pub fn sysroot_dir(&self) -> Result<PathBuf> {
+        util::absolute(&self.output).with_context(|| {
+            format!(
+                "Couldn't get canonical path to sysroot: {}",
+                self.output.display()
+            )
+        })
+    }
+
+    /// The absolute path to the directory the sysroot crates for `target`
+    /// are put in, `lib/rustlib/<target>/lib`.
+    ///
+    /// This is what `rustc --print target-libdir` would print when using
+    /// the sysroot. The sysroot doesn't have to be built yet.
+    ///
+    /// # Errors
+    ///
+    /// - If `target` isn't a valid target.
+    /// - See [`SysrootBuilder::sysroot_dir`].
+    pub fn target_libdir(&self, target: &Path) -> Result<PathBuf> {
+        artifact_dir(&self.sysroot_dir()?, target)
+    }
+
+    /// The flags to pass to rustc so it uses the sysroot.
+    ///
+    /// # Errors
+    ///
+    /// - See [`SysrootBuilder::sysroot_dir`].
+    pub fn sysroot_rustflags(&self) -> Result<Vec<OsString>> {
+        Ok(vec!["--sysroot".into(), self.sysroot_dir()?.into()])
+    }
+
+    /// Lock the sysroot, waiting for any other process building it.
+    ///
+    /// The lock is released when the returned file is dropped.
+    fn lock(&self) -> Result<File> {
+        let path = self.output.join(".cargo-sysroot.lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Couldn't open lock file {}", path.display()))?;
+        if file.try_lock_exclusive().is_err() {
+            self.observer.event(BuildEvent::WaitingForLock(&path));
+            file.lock_exclusive()
+                .with_context(|| format!("Couldn't lock {}", path.display()))?;
+        }
+        Ok(file)
+    }
+
+    /// Remove things from the sysroot, see [`Clean`] for what.
+    ///
+    /// Returns the paths that were removed, or if `dry_run` is `true`,
+    /// the paths that would have been removed, without removing anything.
+    /// Paths that don't exist are not included.
+    ///
+    /// This uses the paths from [`SysrootBuilder::output`] and
+    /// [`SysrootBuilder::target_dir`].
+    ///
+    /// # Errors
+    ///
+    /// - If cleaning [`Clean::HostTools`] when the host is one of the
+    ///   [`SysrootBuilder::target`]s.
+    pub fn clean(&self, clean: &Clean, dry_run: bool) -> Result<Vec<PathBuf>> {
+        let paths = match clean {
+            Clean::All => vec![self.output.clone(), self.build_dir()],
+            Clean::Target(target) => vec![self
+                .output
+                .join("lib")
+                .join("rustlib")
+                .join(target_name(target)?)],
+            Clean::BuildDir => vec![self.build_dir()],
+            Clean::HostTools => {
+                let host = util::get_host_target().context("Couldn't get host target")?;
+                // See `build`, the host tools aren't copied over a built host sysroot.
+                if self.targets.iter().any(|t| t.as_os_str() == host.as_str()) {
+                    return Err(anyhow!(
+                        "The host target `{}` is built into the sysroot, so there are no host \
+                         tools to remove. Clean the target instead",
+                        host
+                    ));
+                }
+                vec![self.output.join("lib").join("rustlib").join(host)]
+            }
+        };
+        let _lock = if dry_run || !self.output.exists() {
+            None
+        } else {
+            Some(self.lock()?)
+        };
+        let mut removed = Vec::with_capacity(paths.len());
+        for path in paths {
+            // The build directory is inside the sysroot by default.
+            if !path.exists() || removed.iter().any(|r: &PathBuf| path.starts_with(r)) {
+                continue;
+            }
+            if !dry_run {
+                clean_artifacts(&path)
+                    .with_context(|| format!("Couldn't remove {}", path.display()))?;
+            }
+            removed.push(path);
+        }
+        Ok(removed)
+    }
+
+    /// A target to compile *for*. This can be a target-triple,
     /// or a [JSON Target Specification][1].
     ///
-    /// By default this is `None`, and if not set when
+    /// This *adds* to, not *replaces*, any previous calls to this method.
+    /// Every target gets its own `lib/rustlib/<target>/lib` directory
+    /// in the same sysroot.
+    ///
+    /// By default there are no targets, and if none are set when
     /// [`SysrootBuilder::build`] is called, will cause an error.
     ///
     /// [1]: https://doc.rust-lang.org/rustc/targets/custom.html
     pub fn target(&mut self, target: PathBuf) -> &mut Self {
-        self.target = Some(target);
+        self.targets.push(target);
+        self
+    }
+
+    /// The targets to compile for.
+    ///
+    /// See [`SysrootBuilder::target`].
+    pub fn target_list(&self) -> &[PathBuf] {
+        &self.targets
+    }
+
+    /// Multiple targets to compile *for*.
+    ///
+    /// This *adds* to, not *replaces*, any previous calls to this method,
+    /// or [`SysrootBuilder::target`].
+    ///
+    /// See [`SysrootBuilder::target`] for details.
+    pub fn targets<I, P>(&mut self, targets: I) -> &mut Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.targets.extend(targets.into_iter().map(Into::into));
         self
     }
 
@@ -191,6 +785,68 @@ impl SysrootBuilder {
         self
     }
 
+    /// Which `std` features to enable, for [`Sysroot::Std`] and
+    /// [`Sysroot::Test`].
+    ///
+    /// This *adds* to, not *replaces*, any previous calls to this method.
+    ///
+    /// By default this is empty, so only the `panic_abort` runtime is
+    /// built. Targets that unwind on panic need [`StdFeature::PanicUnwind`].
+    ///
+    /// See [`StdFeature`] for details.
+    pub fn std_features(&mut self, features: &[StdFeature]) -> &mut Self {
+        self.std_features.extend_from_slice(features);
+        self.std_features.sort_unstable();
+        self.std_features.dedup();
+        self
+    }
+
+    /// Library crates from `rust-src` to build, by package name, in addition
+    /// to the [`Sysroot`] crates.
+    ///
+    /// This allows combinations [`Sysroot`] can't express, like
+    /// `compiler_builtins` and `panic_abort` with [`Sysroot::Core`].
+    /// Any patches from the library workspace they need are applied.
+    ///
+    /// The [`Sysroot`] crates `core`, `alloc`, `std`, and `test` can't be
+    /// added this way, and are an error when building.
+    ///
+    /// This *adds* to, not *replaces*, any previous calls to this method.
+    ///
+    /// By default this is empty.
+    pub fn extra_crates<I, S>(&mut self, crates: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        for name in crates {
+            let name = name.into();
+            if !self.extra_crates.contains(&name) {
+                self.extra_crates.push(name);
+            }
+        }
+        self
+    }
+
+    /// Build the crate `name` from `source`, instead of from `rust-src` or
+    /// crates.io.
+    ///
+    /// Crates in `rust-src` can only be overridden with a
+    /// [`CrateOverride::Path`]. Every other crate in `rust-src` uses the
+    /// override instead, through a copy of `rust-src` in the sysroot made of
+    /// links. Other crates are overridden with `[patch.crates-io]`.
+    ///
+    /// Overrides are listed in [`BuildReport::overrides`], and reported with
+    /// [`BuildEvent::Override`].
+    ///
+    /// This *replaces* any previous calls to this method for `name`.
+    ///
+    /// By default there are none.
+    pub fn override_crate<S: Into<String>>(&mut self, name: S, source: CrateOverride) -> &mut Self {
+        self.overrides.insert(name.into(), source);
+        self
+    }
+
     /// Custom flags to pass to **all** `rustc` compiler invocations.
     ///
     /// This *adds* to, not *replaces*, any previous calls to this method.
@@ -213,20 +869,432 @@ impl SysrootBuilder {
         self
     }
 
-    /// Build the Sysroot, and return a path suitable to pass to rustc.
+    /// Settings for just the crate `name`, on top of those for every crate.
+    ///
+    /// [`CrateSettings::profile`] is added to the generated `Cargo.toml` as
+    /// `[profile.<profile>.package.<name>]`, over any from
+    /// [`SysrootBuilder::manifest`].
+    /// [`CrateSettings::rustflags`] are added after
+    /// [`SysrootBuilder::rustc_flags`], for just this crate.
+    ///
+    /// This *replaces* any previous calls to this method for `name`.
+    ///
+    /// By default there are none.
+    pub fn crate_settings<S: Into<String>>(
+        &mut self,
+        name: S,
+        settings: CrateSettings,
+    ) -> &mut Self {
+        self.crate_settings.insert(name.into(), settings);
+        self
+    }
+
+    /// The program to use as the rustc wrapper for
+    /// [`CrateSettings::rustflags`].
+    ///
+    /// It must call [`wrap_rustc`] at the start of `main`.
+    /// It's only used if some crate has [`CrateSettings::rustflags`], and
+    /// any existing `RUSTC_WRAPPER` is still run, by it.
+    ///
+    /// By default there is none, and [`CrateSettings::rustflags`] can't be
+    /// used.
+    pub fn rustc_wrapper(&mut self, wrapper: PathBuf) -> &mut Self {
+        self.rustc_wrapper = Some(wrapper);
+        self
+    }
+
+    /// The environment for running `rustc` through the rustc wrapper, if any
+    /// crate has [`CrateSettings::rustflags`].
+    fn rustc_wrapper_env(&self) -> Result<Vec<(&'static str, OsString)>> {
+        let flags: BTreeMap<_, _> = self
+            .crate_settings
+            .iter()
+            .filter(|(_, s)| !s.rustflags.is_empty())
+            .map(|(name, s)| (name.replace('-', "_"), &s.rustflags))
+            .collect();
+        if flags.is_empty() {
+            return Ok(Vec::new());
+        }
+        let wrapper = self.rustc_wrapper.clone().with_context(|| {
+            format!(
+                "`{}` has rustflags, which need SysrootBuilder::rustc_wrapper",
+                flags.keys().next().unwrap()
+            )
+        })?;
+        let mut env = vec![
+            ("RUSTC_WRAPPER", wrapper.into()),
+            (
+                wrapper::RUSTFLAGS_VAR,
+                serde_json::to_string(&flags)?.into(),
+            ),
+        ];
+        match env::var_os("RUSTC_WRAPPER") {
+            Some(inner) if !inner.is_empty() => env.push((wrapper::INNER_WRAPPER_VAR, inner)),
+            _ => (),
+        }
+        Ok(env)
+    }
+
+    /// The C toolchain to use for `target`, when building with
+    /// [`Features::CompilerBuiltinsC`].
+    ///
+    /// `target` is the target triple, or the name of a JSON specification
+    /// without its extension, see [`target_name`].
+    ///
+    /// This *replaces* any previous calls to this method for `target`.
+    ///
+    /// By default there are none, see [`CToolchain`] for what that means.
+    pub fn c_toolchain<S: Into<String>>(&mut self, target: S, toolchain: CToolchain) -> &mut Self {
+        self.c_toolchains.insert(target.into(), toolchain);
+        self
+    }
+
+    /// Whether `compiler_builtins` is built with
+    /// [`Features::CompilerBuiltinsC`].
+    fn compiler_builtins_c(&self) -> bool {
+        self.features.contains(&Features::CompilerBuiltinsC)
+            && (self.sysroot_crate != Sysroot::Core
+                || self.features.contains(&Features::CompilerBuiltinsNoAsm)
+                || self
+                    .extra_crates
+                    .iter()
+                    .any(|c| c.replace('-', "_") == "compiler_builtins"))
+    }
+
+    /// The environment variables `cc` uses to find the C toolchain for
+    /// `target`.
+    fn c_toolchain_env(&self, target: &Path) -> Result<Vec<(String, OsString)>> {
+        let name = target_name(target)?
+            .to_str()
+            .context("Target name was not valid utf-8")?;
+        let mut env = Vec::new();
+        if let Some(toolchain) = self.c_toolchains.get(name) {
+            let mut vars: Vec<(_, OsString)> = Vec::new();
+            if let Some(cc) = &toolchain.cc {
+                vars.push(("CC", cc.into()));
+            }
+            if let Some(ar) = &toolchain.ar {
+                vars.push(("AR", ar.into()));
+            }
+            if !toolchain.cflags.is_empty() {
+                vars.push(("CFLAGS", toolchain.cflags.join(" ").into()));
+            }
+            // `cc` checks both, so set both to override the environment.
+            for (var, value) in vars {
+                env.push((format!("{}_{}", var, name), value.clone()));
+                env.push((format!("{}_{}", var, name.replace('-', "_")), value));
+            }
+        }
+        Ok(env)
+    }
+
+    /// The [Cargo Profile][1] to build the sysroot crates with.
+    ///
+    /// This can be `dev`, `release`, or any custom profile defined in
+    /// [`SysrootBuilder::manifest`]. `test`, `bench`, and `check` are an
+    /// error when building, because cargo doesn't build libraries with them.
+    ///
+    /// By default this is `release`.
+    ///
+    /// [1]: https://doc.rust-lang.org/stable/cargo/reference/profiles.html
+    pub fn profile<S: Into<String>>(&mut self, profile: S) -> &mut Self {
+        self.profile = profile.into();
+        self
+    }
+
+    /// Profile settings for the sysroot crates, in the same form as the
+    /// `[profile]` table of a `Cargo.toml`.
+    ///
+    /// These are merged over the profiles copied from
+    /// [`SysrootBuilder::manifest`], so the sysroot can keep some of their
+    /// settings and change others. Tables are merged key by key, anything
+    /// else is replaced.
+    ///
+    /// This *merges* into any previous calls to this method.
+    ///
+    /// By default there are none. See [`SysrootBuilder::profiles`] for the
+    /// result.
+    pub fn profile_overrides(&mut self, overrides: toml::value::Table) -> &mut Self {
+        util::merge_toml(&mut self.profile_overrides, overrides);
+        self
+    }
+
+    /// The `[profile]` table the sysroot crates are built with.
+    ///
+    /// This is the profiles from [`SysrootBuilder::manifest`], with
+    /// [`SysrootBuilder::profile_overrides`] and
+    /// [`CrateSettings::profile`] merged over them.
+    ///
+    /// It's also in [`BuildReport::profiles`].
+    ///
+    /// # Errors
+    ///
+    /// - If `manifest` couldn't be read.
+    pub fn profiles(&self) -> Result<toml::value::Table> {
+        let mut profiles = match &self.manifest {
+            Some(manifest) => util::get_manifest_profile(manifest)?.unwrap_or_default(),
+            None => Default::default(),
+        };
+        util::merge_toml(&mut profiles, self.profile_overrides.clone());
+        for (name, settings) in &self.crate_settings {
+            if settings.profile.is_empty() {
+                continue;
+            }
+            let mut package = toml::value::Table::new();
+            package.insert(name.clone(), settings.profile.clone().into());
+            let mut profile = toml::value::Table::new();
+            profile.insert("package".into(), package.into());
+            let mut over = toml::value::Table::new();
+            over.insert(self.profile.clone(), profile.into());
+            util::merge_toml(&mut profiles, over);
+        }
+        Ok(profiles)
+    }
+
+    /// Whether to build without accessing the network, like cargo's
+    /// `--offline`.
+    ///
+    /// Any dependencies must already be downloaded, or be available from
+    /// [`SysrootBuilder::crate_source`].
+    ///
+    /// By default this is `false`.
+    pub fn offline(&mut self, offline: bool) -> &mut Self {
+        self.offline = offline;
+        self
+    }
+
+    /// Whether to require the sysroots `Cargo.lock` be up to date, and not
+    /// access the network, like cargo's `--frozen`.
+    ///
+    /// By default this is `false`.
+    pub fn frozen(&mut self, frozen: bool) -> &mut Self {
+        self.frozen = frozen;
+        self
+    }
+
+    /// Where to get the dependencies of the sysroot crates from, instead of
+    /// crates.io.
+    ///
+    /// By default this is `None`, and crates.io is used.
+    ///
+    /// See [`CrateSource`] for details.
+    pub fn crate_source(&mut self, source: CrateSource) -> &mut Self {
+        self.crate_source = Some(source);
+        self
+    }
+
+    /// The `Cargo.lock` to build the sysroot crates with.
+    ///
+    /// This is copied into the sysroot before building, and is updated by
+    /// cargo as needed, unless [`SysrootBuilder::locked`] is set.
+    /// A good choice is the `Cargo.lock` in the sysroot after a
+    /// previous build.
+    ///
+    /// By default this is the `Cargo.lock` in
+    /// [`SysrootBuilder::rust_src`], so dependencies are the same versions
+    /// the nightly was tested with.
+    pub fn lockfile(&mut self, lockfile: PathBuf) -> &mut Self {
+        self.lockfile = Some(lockfile);
+        self
+    }
+
+    /// Whether to require that [`SysrootBuilder::lockfile`] is up to date,
+    /// like cargo's `--locked`.
+    ///
+    /// This requires [`SysrootBuilder::lockfile`] to be set, because the
+    /// `Cargo.lock` in `rust-src` never exactly matches the sysroot.
+    ///
+    /// By default this is `false`.
+    pub fn locked(&mut self, locked: bool) -> &mut Self {
+        self.locked = locked;
+        self
+    }
+
+    /// Extra arguments to pass to every cargo command.
+    fn cargo_args(&self) -> Result<Vec<OsString>> {
+        let mut args: Vec<OsString> = Vec::new();
+        if self.offline {
+            args.push("--offline".into());
+        }
+        if self.frozen {
+            args.push("--frozen".into());
+        }
+        if self.locked {
+            args.push("--locked".into());
+        }
+        if let Some(source) = &self.crate_source {
+            let (kind, path) = match source {
+                CrateSource::Vendor(path) => ("directory", path),
+                CrateSource::LocalRegistry(path) => ("local-registry", path),
+            };
+            let path = util::absolute(path)
+                .with_context(|| format!("Couldn't find crate source {}", path.display()))?;
+            let path = path
+                .to_str()
+                .context("Crate source path was not valid utf-8")?;
+            args.push("--config".into());
+            args.push("source.crates-io.replace-with = \"cargo-sysroot\"".into());
+            args.push("--config".into());
+            args.push(
+                format!(
+                    "source.cargo-sysroot.{} = {}",
+                    kind,
+                    toml::Value::from(path)
+                )
+                .into(),
+            );
+        }
+        Ok(args)
+    }
+
+    /// Whether to always rebuild the sysroot, even if it's up to date.
+    ///
+    /// By default this is `false`.
+    ///
+    /// See [`SysrootBuilder::build`] for what up to date means.
+    pub fn force(&mut self, force: bool) -> &mut Self {
+        self.force = force;
+        self
+    }
+
+    /// Watch the progress of [`SysrootBuilder::build`], and capture the
+    /// output from cargo.
+    ///
+    /// This *replaces* any previous calls to this method.
+    ///
+    /// By default this is [`PrintObserver`], which passes cargo output
+    /// through to stderr.
+    ///
+    /// See [`BuildEvent`] for details.
+    pub fn observer<O: BuildObserver + 'static>(&mut self, observer: O) -> &mut Self {
+        self.observer = Observer(Arc::new(observer));
+        self
+    }
+
+    /// Check [`SysrootBuilder::std_features`] exist in `rust_src`, and the
+    /// panic runtime matches every targets panic strategy.
+    fn check_std(&self, rust_src: &Path) -> Result<()> {
+        let available = util::get_manifest_features(&rust_src.join("std").join("Cargo.toml"))?;
+        for feature in &self.std_features {
+            if !available.iter().any(|f| f == feature.name()) {
+                return Err(anyhow!(
+                    "The std feature `{}` isn't supported by this rust-src",
+                    feature.name()
+                ));
+            }
+        }
+
+        let unwind = self.std_features.contains(&StdFeature::PanicUnwind);
+        // Later flags win, and `build_alloc` puts ours after `RUSTFLAGS`,
+        // which cargo puts after the profile.
+        let env_flags: Vec<OsString> = env::var("RUSTFLAGS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(Into::into)
+            .collect();
+        let configured = match util::panic_flag(&self.rustc_flags) {
+            Some(strategy) => Some(strategy),
+            None => match util::panic_flag(&env_flags) {
+                Some(strategy) => Some(strategy),
+                None => self.profile_panic()?,
+            },
+        };
+        for target in &self.targets {
+            let strategy = match &configured {
+                Some(strategy) => strategy.clone(),
+                None => util::get_panic_strategy(target).with_context(|| {
+                    format!("Couldn't get panic strategy for {}", target.display())
+                })?,
+            };
+            match (strategy.as_str(), unwind) {
+                ("unwind", false) => {
+                    return Err(anyhow!(
+                        "`{}` unwinds on panic, which needs the `panic-unwind` std feature. \
+                         Enable it, or set `panic = \"abort\"` in the profile",
+                        target.display()
+                    ))
+                }
+                ("unwind", true) | (_, false) => (),
+                (strategy, true) => {
+                    return Err(anyhow!(
+                        "The `panic-unwind` std feature can't be used with `{}`, whose panic \
+                         strategy is `{}`",
+                        target.display(),
+                        strategy
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The `panic` setting of [`SysrootBuilder::profile`], following
+    /// `inherits`, if it has one.
+    fn profile_panic(&self) -> Result<Option<String>> {
+        let profiles = self.profiles()?;
+        let mut name = self.profile.as_str();
+        for _ in 0..profiles.len() + 1 {
+            let profile = profiles.get(name).and_then(toml::Value::as_table);
+            if let Some(panic) = profile.and_then(|p| p.get("panic")) {
+                return Ok(Some(
+                    panic
+                        .as_str()
+                        .with_context(|| format!("`profile.{}.panic` was not a string", name))?
+                        .into(),
+                ));
+            }
+            name = match profile
+                .and_then(|p| p.get("inherits"))
+                .and_then(|i| i.as_str())
+            {
+                Some(inherits) => inherits,
+                None => return Ok(None),
+            };
+        }
+        Err(anyhow!("Profile `{}` inherits from itself", self.profile))
+    }
+
+    /// Build the Sysroot, and return a [`BuildReport`] with a path suitable
+    /// to pass to rustc, and what was built.
+    ///
+    /// The sysroot crates are built once for every target, using the same
+    /// generated manifest.
+    ///
+    /// Targets are skipped if they were previously built with the same
+    /// rustc version, `rust-src`, target specification, crates, [`Features`],
+    /// rustc flags, and profiles, unless [`SysrootBuilder::force`] is set.
+    /// This is recorded in a fingerprint file in the sysroot.
+    ///
+    /// Only one process can build a sysroot at a time, others wait for a
+    /// lock file in [`SysrootBuilder::output`].
+    /// The new sysroot crates for a target are only put in place once they've
+    /// all been built, so if the build fails the previous ones are kept.
     ///
     /// # Errors
     ///
     /// - [`SysrootBuilder::target`] was not called
     /// - If `manifest` is provided and does not exist
-    /// - If `target` is a JSON specification, but doesn't exist.
+    /// - If [`SysrootBuilder::profile`] is a custom profile not defined in
+    ///   `manifest`, or is `test`, `bench`, or `check`.
+    /// - If any `target` is a JSON specification, but doesn't exist.
     /// - If the `rust_src` directory does not exist, or could not be detected.
+    /// - If [`SysrootBuilder::lockfile`] is set and does not exist, or
+    ///   [`SysrootBuilder::locked`] is set without it.
+    /// - If [`Features::CompilerBuiltinsC`] is enabled, but there's no C
+    ///   compiler for a target, or `RUST_COMPILER_RT_ROOT` isn't set when
+    ///   `rust-src` needs it.
+    /// - If any [`SysrootBuilder::extra_crates`] are [`Sysroot`] crates.
+    /// - If [`SysrootBuilder::std_features`] are set without [`Sysroot::Std`]
+    ///   or [`Sysroot::Test`], or aren't in `rust-src`.
+    /// - If building `std`, and the panic runtime doesn't match a target's
+    ///   panic strategy. See [`StdFeature::PanicUnwind`].
+    /// - If any [`CrateSettings::rustflags`] are set without
+    ///   [`SysrootBuilder::rustc_wrapper`].
     /// - If the sysroot cannot be setup, or fails to compile
-    pub fn build(&self) -> Result<PathBuf> {
-        let target = match &self.target {
-            Some(t) => t,
-            None => return Err(anyhow!("SysrootBuilder::target was not called")),
-        };
+    pub fn build(&self) -> Result<BuildReport> {
+        if self.targets.is_empty() {
+            return Err(anyhow!("SysrootBuilder::target was not called"));
+        }
         if let Some(manifest) = &self.manifest {
             if !manifest.exists() {
                 return Err(anyhow!(
@@ -235,10 +1303,56 @@ impl SysrootBuilder {
                 ));
             }
         }
-        // If `target` has an extension, assume target spec...
-        if target.extension().is_some() {
-            // ...and check if it exists.
-            if !target.exists() {
+        // These need the features and checks that come with the `Sysroot` level.
+        if let Some(name) = self
+            .extra_crates
+            .iter()
+            .find(|c| matches!(c.as_str(), "core" | "alloc" | "std" | "test"))
+        {
+            return Err(anyhow!(
+                "`{}` can't be an extra crate, set it as the Sysroot instead, \
+                 with `--crates {}` or `crates = \"{}\"`",
+                name,
+                name,
+                name
+            ));
+        }
+        match self.profile.as_str() {
+            "dev" | "release" => (),
+            // Cargo builds a test harness, or doesn't codegen, with these.
+            profile @ ("test" | "bench" | "check") => {
+                return Err(anyhow!(
+                    "The `{}` profile can't be used to build the sysroot, use `dev`, \
+                     `release`, or a custom profile",
+                    profile
+                ));
+            }
+            profile => {
+                if !self.profiles()?.contains_key(profile) {
+                    return Err(anyhow!(
+                        "Unknown profile `{}`, it must be `dev`, `release`, or defined in \
+                         the manifest",
+                        profile
+                    ));
+                }
+            }
+        }
+        match &self.lockfile {
+            Some(lockfile) if !lockfile.exists() => {
+                return Err(anyhow!(
+                    "Provided lockfile did not exist: {}",
+                    lockfile.display()
+                ));
+            }
+            None if self.locked => {
+                return Err(anyhow!(
+                    "SysrootBuilder::locked requires SysrootBuilder::lockfile"
+                ));
+            }
+            _ => (),
+        }
+        for target in &self.targets {
+            if is_target_spec(target) && !target.exists() {
                 return Err(anyhow!(
                     "Provided JSON Target Specification did not exist: {}",
                     target.display()
@@ -263,35 +1377,163 @@ impl SysrootBuilder {
                 src
             }
         };
-        fs::create_dir_all(&self.output).context("Couldn't create sysroot output directory")?;
-        fs::create_dir_all(artifact_dir(&self.output, target)?)
-            .context("Failed to setup sysroot directory structure")?;
-
-        let sysroot_cargo_toml = generate_sysroot_cargo_toml(&SysrootBuilder {
-            // HACK: So it can see auto-detected rust-src.
+        self.rustc_wrapper_env()?;
+        if self.compiler_builtins_c() {
+            for target in &self.targets {
+                util::check_c_compiler(target_name(target)?, &self.c_toolchain_env(target)?)?;
+            }
+            // The in-tree version doesn't bundle the `compiler-rt` sources.
+            let in_tree = rust_src.join("compiler-builtins").join("compiler-builtins");
+            if in_tree.join("Cargo.toml").exists()
+                && !self.overrides.contains_key("compiler_builtins")
+                && env::var_os("LLVM_COMPILER_RT_LIB").is_none()
+            {
+                match env::var_os("RUST_COMPILER_RT_ROOT") {
+                    Some(root) if Path::new(&root).exists() => (),
+                    Some(root) => {
+                        return Err(anyhow!(
+                            "RUST_COMPILER_RT_ROOT was set to `{}`, which did not exist",
+                            Path::new(&root).display()
+                        ));
+                    }
+                    None => {
+                        return Err(anyhow!(
+                            "The `c` feature needs RUST_COMPILER_RT_ROOT set to the \
+                             `compiler-rt` sources for this rust-src"
+                        ));
+                    }
+                }
+            }
+        }
+        if self.sysroot_crate.has_std() {
+            self.check_std(&rust_src)?;
+        } else if !self.std_features.is_empty() {
+            return Err(anyhow!(
+                "SysrootBuilder::std_features requires Sysroot::Std or Sysroot::Test"
+            ));
+        }
+        self.observer.event(BuildEvent::RustSrc(&rust_src));
+        // HACK: So it can see auto-detected rust-src.
+        let builder = &SysrootBuilder {
             rust_src: Some(rust_src),
             ..self.clone()
-        })?;
-        build_alloc(&sysroot_cargo_toml, &self).context("Failed to build sysroot")?;
+        };
+        fs::create_dir_all(&self.output).context("Couldn't create sysroot output directory")?;
+        let _lock = self.lock()?;
+        for target in &self.targets {
+            util::recover_dir(&artifact_dir(&self.output, target)?)?;
+        }
+
+        let mut stale = Vec::with_capacity(self.targets.len());
+        let mut reports: Vec<_> = self
+            .targets
+            .iter()
+            .map(|t| TargetReport::new(t.clone(), true))
+            .collect();
+        for target in &self.targets {
+            let fingerprint = Fingerprint::new(builder, target).with_context(|| {
+                format!("Couldn't fingerprint sysroot for {}", target.display())
+            })?;
+            if self.force || !fingerprint.is_fresh(&self.output, target)? {
+                stale.push((target, fingerprint));
+            } else {
+                self.observer.event(BuildEvent::UpToDate { target });
+            }
+        }
+
+        if !stale.is_empty() {
+            self.observer.event(BuildEvent::GenerateManifest(
+                &self.output.join("Cargo.toml"),
+            ));
+            for (name, source) in &self.overrides {
+                self.observer.event(BuildEvent::Override { name, source });
+            }
+            let builder = &SysrootBuilder {
+                rust_src: Some(override_rust_src(builder)?),
+                ..builder.clone()
+            };
+            let sysroot_cargo_toml = generate_sysroot_cargo_toml(builder)?;
+            seed_lockfile(builder)?;
+            for (target, fingerprint) in stale {
+                self.observer.event(BuildEvent::Building { target });
+                let report = reports.iter_mut().find(|r| &r.target == target).unwrap();
+                report.up_to_date = false;
+                build_alloc(&sysroot_cargo_toml, self, target, report)
+                    .with_context(|| format!("Failed to build sysroot for {}", target.display()))?;
+                fingerprint.write(&self.output, target)?;
+            }
+        }
 
         // Copy host tools to the new sysroot, so that stuff like proc-macros and
         // testing can work.
-        util::copy_host_tools(&self.output).context("Couldn't copy host tools to sysroot")?;
-        Ok(self.output.canonicalize().with_context(|| {
-            format!(
-                "Couldn't get canonical path to sysroot: {}",
-                self.output.display()
-            )
-        })?)
+        // Unless we just built the host target, which they'd conflict with.
+        let host = util::get_host_target().context("Couldn't get host target")?;
+        if !self.targets.iter().any(|t| t.as_os_str() == host.as_str()) {
+            self.observer.event(BuildEvent::CopyHostTools);
+            util::copy_host_tools(&self.output).context("Couldn't copy host tools to sysroot")?;
+        }
+        Ok(BuildReport {
+            sysroot: self.sysroot_dir()?,
+            targets: reports,
+            overrides: self.overrides.clone(),
+            profiles: self.profiles()?,
+        })
     }
 }
 
+/// The `rust-src` to build from, with any [`CrateOverride::Path`] for its
+/// crates in place.
+///
+/// Should ONLY be called by [`SysrootBuilder::build`].
+///
+/// Library crates depend on each other by relative path, so overridden
+/// crates are linked into a copy of `rust-src` in the sysroot.
+fn override_rust_src(builder: &SysrootBuilder) -> Result<PathBuf> {
+    let rust_src = builder.rust_src.as_ref().unwrap();
+    let mut links = Vec::new();
+    for (name, source) in &builder.overrides {
+        let dir = match library::find_crate(rust_src, name) {
+            Ok((_, dir)) => dir,
+            Err(_) => continue,
+        };
+        let path = match source {
+            CrateOverride::Path { path } => path,
+            CrateOverride::Git { .. } => {
+                return Err(anyhow!(
+                    "`{}` is in rust-src, so it can only be overridden with a path, not git. \
+                     Use a path to a checkout",
+                    name
+                ))
+            }
+        };
+        if !path.join("Cargo.toml").exists() {
+            return Err(anyhow!(
+                "Override for `{}` did not exist: {}",
+                name,
+                path.display()
+            ));
+        }
+        links.push((
+            dir.strip_prefix(rust_src).unwrap().to_path_buf(),
+            util::absolute(path)?,
+        ));
+    }
+    let shadow = builder.output.join("library");
+    clean_artifacts(&shadow)?;
+    if links.is_empty() {
+        return Ok(rust_src.clone());
+    }
+    library::link(rust_src, &shadow, &links).context("Couldn't link overrides into rust-src")?;
+    util::absolute(&shadow)
+}
+
 /// Generate a Cargo.toml for building the sysroot crates
 ///
 /// Should ONLY be called by [`SysrootBuilder::build`].
 ///
 /// See [`build_sysroot_with`].
 fn generate_sysroot_cargo_toml(builder: &SysrootBuilder) -> Result<PathBuf> {
+    let rust_src = builder.rust_src.as_ref().unwrap();
     fs::write(
         builder.output.join("lib.rs"),
         "#![feature(no_core)]\n#![no_core]",
@@ -326,20 +1568,7 @@ fn generate_sysroot_cargo_toml(builder: &SysrootBuilder) -> Result<PathBuf> {
                 }
 
                 Sysroot::CompilerBuiltins => {
-                    deps.insert(
-                        "compiler_builtins".into(),
-                        Dependency::Full(DependencyFull {
-                            version: Some("0.1".into()),
-                            features: {
-                                let mut f = vec!["rustc-dep-of-std".into()];
-                                if builder.features.contains(&Features::CompilerBuiltinsMem) {
-                                    f.push("mem".into());
-                                }
-                                Some(f)
-                            },
-                            ..Default::default()
-                        }),
-                    );
+                    deps.insert("compiler_builtins".into(), compiler_builtins(builder));
                 }
 
                 Sysroot::Alloc => {
@@ -347,90 +1576,253 @@ fn generate_sysroot_cargo_toml(builder: &SysrootBuilder) -> Result<PathBuf> {
                         "alloc".into(),
                         Dependency::Full(DependencyFull {
                             path: Some(builder.rust_src.as_ref().unwrap().join("alloc")),
-                            features: if builder.features.contains(&Features::CompilerBuiltinsMem) {
-                                Some(vec!["compiler-builtins-mem".into()])
-                            } else {
-                                None
-                            },
+                            features: alloc_features(builder),
                             ..Default::default()
                         }),
                     );
                 }
 
-                Sysroot::Std => {
+                Sysroot::Std | Sysroot::Test => {
                     deps.insert(
                         "std".into(),
                         Dependency::Full(DependencyFull {
                             path: Some(builder.rust_src.as_ref().unwrap().join("std")),
-                            features: if builder.features.contains(&Features::CompilerBuiltinsMem) {
-                                Some(vec!["compiler-builtins-mem".into()])
-                            } else {
-                                None
+                            features: {
+                                let mut f = alloc_features(builder).unwrap_or_default();
+                                f.extend(builder.std_features.iter().map(|f| f.name().into()));
+                                Some(f).filter(|f| !f.is_empty())
                             },
                             ..Default::default()
                         }),
                     );
+                    if builder.sysroot_crate == Sysroot::Test {
+                        for name in &["test", "proc_macro"] {
+                            deps.insert(
+                                name.to_string(),
+                                Dependency::Full(DependencyFull {
+                                    path: Some(builder.rust_src.as_ref().unwrap().join(name)),
+                                    ..Default::default()
+                                }),
+                            );
+                        }
+                    }
                 }
             }
-            deps
-        }),
-        patch: Some(Patches {
-            sources: if let Sysroot::Core = builder.sysroot_crate {
-                BTreeMap::new()
-            } else {
-                let mut sources = BTreeMap::new();
-                sources.insert("crates-io".into(), {
-                    let mut x = BTreeMap::new();
-                    x.insert(
-                        "rustc-std-workspace-core".to_string(),
+            // `no-asm` isn't passed on by `alloc` or `std`, so it's enabled
+            // directly, relying on cargo unifying features.
+            if builder.features.contains(&Features::CompilerBuiltinsNoAsm) {
+                if !matches!(
+                    builder.sysroot_crate,
+                    Sysroot::Core | Sysroot::CompilerBuiltins
+                ) && in_tree_compiler_builtins(builder).is_some()
+                {
+                    return Err(anyhow!(
+                        "The `no-asm` feature is only supported with `core` or \
+                         `compiler-builtins` for this rust-src, because its compiler_builtins \
+                         has no `no-asm` feature, and `alloc` enables its assembly"
+                    ));
+                }
+                deps.insert("compiler_builtins".into(), compiler_builtins(builder));
+            }
+            for name in &builder.extra_crates {
+                let (name, path) = library::find_crate(rust_src, name)?;
+                if name == "compiler_builtins" {
+                    deps.insert(name, compiler_builtins(builder));
+                } else {
+                    // Keep any features from above.
+                    deps.entry(name).or_insert_with(|| {
                         Dependency::Full(DependencyFull {
-                            path: Some(
-                                builder
-                                    .rust_src
-                                    .as_ref()
-                                    .unwrap()
-                                    .join("rustc-std-workspace-core"),
-                            ),
+                            path: Some(path),
                             ..Default::default()
-                        }),
-                    );
-                    x
-                });
-                sources
-            },
-        }),
-        profile: {
-            match &builder.manifest {
-                Some(manifest) => {
-                    let toml: CargoToml =
-                        from_path(manifest).with_context(|| manifest.display().to_string())?;
-                    toml.profile
+                        })
+                    });
                 }
-                None => None,
             }
-        },
+            deps
+        }),
         ..Default::default()
     };
+    // Profiles are copied as-is, `cargo_toml2` doesn't support custom ones.
+    let mut toml = toml::Value::try_from(&toml).context("Failed writing sysroot Cargo.toml")?;
+    let mut patches: toml::value::Table = library::patches(rust_src, &toml, &builder.output)
+        .context("Couldn't resolve library workspace patches")?
+        .into_iter()
+        .map(|(name, path)| {
+            let mut dep = toml::value::Table::new();
+            dep.insert("path".into(), path.to_string_lossy().into_owned().into());
+            (name, dep.into())
+        })
+        .collect();
+    // Overrides for crates in `rust-src` are already in place.
+    for (name, source) in &builder.overrides {
+        if library::find_crate(rust_src, name).is_err() {
+            let source = match source {
+                CrateOverride::Path { path } => CrateOverride::Path {
+                    path: util::absolute(path)?,
+                },
+                git => git.clone(),
+            };
+            patches.insert(
+                name.clone(),
+                toml::Value::try_from(source).context("Failed writing sysroot Cargo.toml")?,
+            );
+        }
+    }
+    if !patches.is_empty() {
+        let mut sources = toml::value::Table::new();
+        sources.insert("crates-io".into(), patches.into());
+        toml.as_table_mut()
+            .unwrap()
+            .insert("patch".into(), sources.into());
+    }
+    let profiles = builder.profiles()?;
+    if !profiles.is_empty() {
+        toml.as_table_mut()
+            .unwrap()
+            .insert("profile".into(), profiles.into());
+    }
     let path = builder.output.join("Cargo.toml");
-    to_path(&path, &toml).context("Failed writing sysroot Cargo.toml")?;
+    fs::write(&path, toml.to_string()).context("Failed writing sysroot Cargo.toml")?;
     Ok(path)
 }
 
+/// The `compiler_builtins` in `rust-src`, if it has one.
+///
+/// Newer `rust-src` includes it, and it's no longer compatible with the
+/// crates.io version.
+fn in_tree_compiler_builtins(builder: &SysrootBuilder) -> Option<PathBuf> {
+    let path = builder
+        .rust_src
+        .as_ref()
+        .unwrap()
+        .join("compiler-builtins")
+        .join("compiler-builtins");
+    path.join("Cargo.toml").exists().then_some(path)
+}
+
+/// The `compiler_builtins` dependency, with the enabled [`Features`].
+fn compiler_builtins(builder: &SysrootBuilder) -> Dependency {
+    let in_tree = in_tree_compiler_builtins(builder);
+    let mut features = vec![if in_tree.is_some() {
+        "compiler-builtins".into()
+    } else {
+        "rustc-dep-of-std".into()
+    }];
+    if builder.features.contains(&Features::CompilerBuiltinsMem) {
+        features.push("mem".into());
+    }
+    if builder.features.contains(&Features::CompilerBuiltinsC) {
+        features.push("c".into());
+    }
+    // The in-tree version replaced `no-asm` with the default `arch` feature.
+    let no_asm = builder.features.contains(&Features::CompilerBuiltinsNoAsm);
+    if no_asm && in_tree.is_none() {
+        features.push("no-asm".into());
+    }
+    Dependency::Full(DependencyFull {
+        version: in_tree.is_none().then(|| "0.1".into()),
+        default_features: (no_asm && in_tree.is_some()).then_some(false),
+        features: Some(features),
+        path: in_tree,
+        ..Default::default()
+    })
+}
+
+/// Features for `alloc` or `std`, which pass them on to `compiler_builtins`.
+fn alloc_features(builder: &SysrootBuilder) -> Option<Vec<String>> {
+    let mut features = Vec::new();
+    if builder.features.contains(&Features::CompilerBuiltinsMem) {
+        features.push("compiler-builtins-mem".into());
+    }
+    if builder.features.contains(&Features::CompilerBuiltinsC) {
+        features.push("compiler-builtins-c".into());
+    }
+    if features.is_empty() {
+        None
+    } else {
+        Some(features)
+    }
+}
+
+/// Copy the `Cargo.lock` to start from into the sysroot.
+///
+/// Should ONLY be called by [`SysrootBuilder::build`], after
+/// [`generate_sysroot_cargo_toml`].
+///
+/// See [`SysrootBuilder::lockfile`].
+fn seed_lockfile(builder: &SysrootBuilder) -> Result<()> {
+    let lockfile = match &builder.lockfile {
+        Some(lockfile) => lockfile.clone(),
+        None => builder.rust_src.as_ref().unwrap().join("Cargo.lock"),
+    };
+    // Old `rust-src` may not have one.
+    if lockfile.exists() {
+        fs::copy(&lockfile, builder.output.join("Cargo.lock"))
+            .with_context(|| format!("Couldn't copy lockfile {}", lockfile.display()))?;
+    }
+    Ok(())
+}
+
 /// The entry-point for building the alloc crate, which builds all the others
 ///
-/// Should ONLY be called by [`SysrootBuilder::build`].
-fn build_alloc(alloc_cargo_toml: &Path, builder: &SysrootBuilder) -> Result<()> {
+/// Should ONLY be called by [`SysrootBuilder::build`], once per target.
+fn build_alloc(
+    alloc_cargo_toml: &Path,
+    builder: &SysrootBuilder,
+    triple: &Path,
+    report: &mut TargetReport,
+) -> Result<()> {
     let path = alloc_cargo_toml;
-    let triple = builder.target.as_ref().unwrap();
-    let target_dir = builder.output.join("target");
+    let target_dir = builder.build_dir();
+    // Cargo puts everything for the target here, and anything for the host
+    // elsewhere.
+    // It has to exist to be canonicalized, like the artifacts cargo reports,
+    // in case the path has symlinks or `..`.
+    fs::create_dir_all(&target_dir)
+        .with_context(|| format!("Couldn't create {}", target_dir.display()))?;
+    let target_artifacts = target_dir
+        .canonicalize()
+        .with_context(|| format!("Couldn't find {}", target_dir.display()))?
+        .join(target_name(triple)?);
 
-    // TODO: Eat output if up to date? Always? On error?
-    let exit = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+    let wrapper_env = builder.rustc_wrapper_env()?;
+    let rustflags = {
+        let mut env = OsString::new();
+        if let Some(exist) = std::env::var_os("RUSTFLAGS") {
+            env.push(exist);
+        }
+        for flag in &builder.rustc_flags {
+            env.push(" ");
+            env.push(flag)
+        }
+        // `std`'s crates.io dependencies need it too, or their items can't be
+        // used by stable `std` APIs. Extra crates may have some.
+        if builder.sysroot_crate.has_std() || !builder.extra_crates.is_empty() {
+            env.push(" -Zforce-unstable-if-unmarked");
+        }
+        // Cargo can't see the flags the rustc wrapper adds, so tell it when
+        // they change.
+        if let Some((_, flags)) = wrapper_env
+            .iter()
+            .find(|(k, _)| *k == wrapper::RUSTFLAGS_VAR)
+        {
+            let mut hasher = DefaultHasher::new();
+            flags.hash(&mut hasher);
+            env.push(format!(" -Cmetadata={:016x}", hasher.finish()));
+        }
+        env
+    };
+    let cargo_args = builder.cargo_args()?;
+    let total = util::count_crates(path, triple, &rustflags, &cargo_args)?;
+    let mut child = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
         .arg("rustc")
-        .arg("--release")
+        .args(&cargo_args)
+        .arg("--profile")
+        .arg(&builder.profile)
+        .arg("--message-format=json")
         .arg("--target")
         // If it doesn't work, assume it's a builtin path?
-        .arg(&triple.canonicalize().unwrap_or_else(|_| triple.into()))
+        .arg(triple.canonicalize().unwrap_or_else(|_| triple.into()))
         .arg("--target-dir")
         .arg(&target_dir)
         .arg("--manifest-path")
@@ -439,22 +1831,95 @@ fn build_alloc(alloc_cargo_toml: &Path, builder: &SysrootBuilder) -> Result<()>
         .arg("-Z")
         // The rust build system only passes this for rustc? xbuild passes this for alloc. 🤷‍♀️
         .arg("force-unstable-if-unmarked")
-        .env("RUSTFLAGS", {
-            let mut env = OsString::new();
-            if let Some(exist) = std::env::var_os("RUSTFLAGS") {
-                env.push(exist);
-            }
-            for flag in &builder.rustc_flags {
-                env.push(" ");
-                env.push(flag)
-            }
-            env
-        })
+        .env("RUSTFLAGS", &rustflags)
+        .envs(builder.c_toolchain_env(triple)?)
+        .envs(wrapper_env)
         // Causes clippy to leak output
         // See #6
         .env_remove("RUSTC_WORKSPACE_WRAPPER")
-        .status()
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
         .context("Couldn't find/run cargo command")?;
+
+    // Both have to be read at the same time, or cargo could block writing to
+    // one of them.
+    let (tx, rx) = mpsc::channel();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let stdout_tx = tx.clone();
+    let threads = [
+        thread::spawn(move || {
+            for line in stdout.lines() {
+                let _ = stdout_tx.send(line.map(|l| (l, true)));
+            }
+        }),
+        thread::spawn(move || {
+            for line in stderr.lines() {
+                let _ = tx.send(line.map(|l| (l, false)));
+            }
+        }),
+    ];
+
+    let mut current = 0;
+    let mut artifacts = Vec::new();
+    for line in rx {
+        let (line, stdout) = line.context("Couldn't read cargo output")?;
+        // Anything else cargo prints, such as from build scripts, is passed on.
+        let message = match stdout.then(|| serde_json::from_str(&line).ok()).flatten() {
+            Some(message) => message,
+            None => {
+                builder.observer.event(BuildEvent::CargoOutput(&line));
+                continue;
+            }
+        };
+        match message {
+            CargoMessage::CompilerArtifact(artifact) => {
+                let (name, version) = artifact.name_version();
+                // Our own placeholder crate, and build scripts.
+                if name == "Sysroot" || artifact.target.kind.iter().any(|k| k == "custom-build") {
+                    continue;
+                }
+                // Build script dependencies, built for the host.
+                if !artifact.filenames.iter().all(|f| {
+                    f.canonicalize()
+                        .is_ok_and(|f| f.starts_with(&target_artifacts))
+                }) {
+                    continue;
+                }
+                current += 1;
+                builder.observer.event(BuildEvent::Compiled {
+                    target: triple,
+                    name: &name,
+                    version: &version,
+                    fresh: artifact.fresh,
+                    current,
+                    total,
+                });
+                report.crates.push(CrateReport {
+                    name,
+                    version,
+                    fresh: artifact.fresh,
+                });
+                artifacts.extend(artifact.filenames);
+            }
+            CargoMessage::CompilerMessage { message } => {
+                if let Some(rendered) = message.rendered {
+                    builder
+                        .observer
+                        .event(BuildEvent::CargoOutput(rendered.trim_end_matches('\n')));
+                    if message.level == "warning" {
+                        report.warnings.push(rendered);
+                    }
+                }
+            }
+            CargoMessage::Other => (),
+        }
+    }
+    for thread in threads {
+        let _ = thread.join();
+    }
+    let exit = child.wait().context("Couldn't wait for cargo")?;
     if !exit.success() {
         return Err(anyhow!(
             "Failed to build sysroot: Exit code {}",
@@ -463,37 +1928,37 @@ fn build_alloc(alloc_cargo_toml: &Path, builder: &SysrootBuilder) -> Result<()>
                 .unwrap_or_else(|| "Killed by signal".to_string())
         ));
     }
+    // Installing nothing would look up to date from then on.
+    if artifacts.is_empty() {
+        return Err(anyhow!(
+            "Built the sysroot, but found no artifacts for it in {}",
+            target_artifacts.display()
+        ));
+    }
 
-    // Copy artifacts to sysroot.
-    for entry in fs::read_dir(
-        target_dir
-            .join(
-                &triple
-                    .file_stem()
-                    .context("Failed to parse target triple")?,
+    // Copy artifacts to sysroot, replacing the old ones,
+    // which would conflict with the new ones.
+    // They're staged first so the old ones survive any failures.
+    builder
+        .observer
+        .event(BuildEvent::CopyArtifacts { target: triple });
+    let artifact_dir = artifact_dir(&builder.output, triple)?;
+    let staging = builder.output.join(".staging").join(target_name(triple)?);
+    clean_artifacts(&staging)?;
+    fs::create_dir_all(&staging).context("Failed to setup sysroot directory structure")?;
+    for artifact in artifacts {
+        let name = artifact.file_name().context("Invalid artifact path")?;
+        let out = staging.join(name);
+        fs::copy(&artifact, &out).with_context(|| {
+            format!(
+                "Copying sysroot artifact from {} to {} failed",
+                artifact.display(),
+                out.display()
             )
-            .join("release")
-            .join("deps"),
-    )
-    .context("Failure to read artifact directory")?
-    {
-        let entry = entry?;
-        let name = entry
-            .file_name()
-            .into_string()
-            .map_err(|e| Error::msg(e.to_string_lossy().to_string()))
-            .context("Invalid Unicode in path")?;
-        if name.starts_with("lib") {
-            let out = artifact_dir(&builder.output, &triple)?.join(name);
-            fs::copy(entry.path(), &out).with_context(|| {
-                format!(
-                    "Copying sysroot artifact from {} to {} failed",
-                    entry.path().display(),
-                    out.display()
-                )
-            })?;
-        }
+        })?;
+        report.artifacts.push(artifact_dir.join(name));
     }
+    util::replace_dir(&staging, &artifact_dir)?;
 
     Ok(())
 }
@@ -505,10 +1970,24 @@ fn artifact_dir(sysroot_dir: &Path, target: &Path) -> Result<PathBuf> {
     Ok(sysroot_dir
         .join("lib")
         .join("rustlib")
-        .join(target.file_stem().context("Invalid Target Specification")?)
+        .join(target_name(target)?)
         .join("lib"))
 }
 
+/// Whether `target` is a JSON Target Specification, rather than a
+/// target-triple, which is assumed if it has an extension.
+pub fn is_target_spec(target: &Path) -> bool {
+    target.extension().is_some()
+}
+
+/// The name of `target`, as used by rustc and cargo for directories.
+///
+/// This is the target triple, or the file name of a JSON specification
+/// without its extension.
+pub fn target_name(target: &Path) -> Result<&OsStr> {
+    target.file_stem().context("Invalid Target Specification")
+}
+
 /// Clean up generated sysroot artifacts.
 ///
 /// Should be called before [`build_sysroot`] if you want this behavior.
@@ -0,0 +1,184 @@
+//! The library workspace in `rust-src`, where the sysroot crates come from.
+use crate::util;
+use anyhow::{anyhow, Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use toml::Value;
+
+/// Find the library crate named `name` in `rust_src`, returning its
+/// package name and directory.
+///
+/// `-` and `_` are treated the same, like cargo does.
+/// The vendored crates.io dependencies are not library crates.
+///
+/// # Errors
+///
+/// - If there's no crate named `name`.
+pub fn find_crate(rust_src: &Path, name: &str) -> Result<(String, PathBuf)> {
+    let normalize = |s: &str| s.replace('-', "_");
+    // `compiler_builtins` is in `compiler-builtins/compiler-builtins`.
+    for dir in fs::read_dir(rust_src)
+        .with_context(|| format!("Couldn't read rust-src at {}", rust_src.display()))?
+    {
+        let dir = dir?.path();
+        if !dir.is_dir() || dir.ends_with("vendor") {
+            continue;
+        }
+        for dir in [dir.clone(), dir.join(dir.file_name().unwrap())] {
+            let manifest = dir.join("Cargo.toml");
+            if !manifest.exists() {
+                continue;
+            }
+            if let Some(found) = package_name(&read(&manifest)?) {
+                if normalize(&found) == normalize(name) {
+                    return Ok((found, dir));
+                }
+            }
+        }
+    }
+    Err(anyhow!(
+        "No library crate named `{}` in rust-src at {}",
+        name,
+        rust_src.display()
+    ))
+}
+
+/// The `[patch.crates-io]` entries from the library workspace that
+/// `manifest`, the parsed `Cargo.toml` in `dir`, needs, by name.
+///
+/// Patches only apply to crates.io dependencies, so none are needed if
+/// `manifest` and its library dependencies don't have any.
+/// Otherwise `rustc-std-workspace-<name>` is needed if `<name>` is used,
+/// and any other patches are needed with `std`, whose crates.io
+/// dependencies use them.
+///
+/// Older `rust-src` without a workspace `Cargo.toml` gets the
+/// `rustc-std-workspace-*` crates.
+pub fn patches(rust_src: &Path, manifest: &Value, dir: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let mut used = Vec::new();
+    let mut registry = false;
+    let mut todo = Vec::new();
+    let mut seen = Vec::new();
+    let mut next = Some((manifest.clone(), dir.to_path_buf()));
+    while let Some((manifest, dir)) = next.take() {
+        used.extend(package_name(&manifest));
+        for (name, dep) in dependencies(&manifest) {
+            match dep.get("path").and_then(Value::as_str) {
+                Some(path) => todo.push(dir.join(path)),
+                None => {
+                    registry = true;
+                    used.push(
+                        dep.get("package")
+                            .and_then(Value::as_str)
+                            .unwrap_or(name)
+                            .to_string(),
+                    );
+                }
+            }
+        }
+        while let Some(dir) = todo.pop() {
+            let dir = dir.canonicalize().unwrap_or(dir);
+            if !seen.contains(&dir) {
+                seen.push(dir.clone());
+                next = Some((read(&dir.join("Cargo.toml"))?, dir));
+                break;
+            }
+        }
+    }
+    if !registry {
+        return Ok(BTreeMap::new());
+    }
+
+    let workspace = rust_src.join("Cargo.toml");
+    let available: BTreeMap<String, PathBuf> = if workspace.exists() {
+        read(&workspace)?
+            .get("patch")
+            .and_then(|p| p.get("crates-io"))
+            .and_then(Value::as_table)
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, dep)| {
+                let path = dep.get("path")?.as_str()?;
+                Some((name.clone(), rust_src.join(path)))
+            })
+            .collect()
+    } else {
+        ["core", "alloc", "std"]
+            .iter()
+            .map(|n| format!("rustc-std-workspace-{}", n))
+            .map(|name| (name.clone(), rust_src.join(name)))
+            .filter(|(_, path)| path.exists())
+            .collect()
+    };
+    let has_std = used.iter().any(|u| u == "std");
+    Ok(available
+        .into_iter()
+        .filter(|(name, _)| {
+            used.contains(name)
+                || match name.strip_prefix("rustc-std-workspace-") {
+                    Some(shim) => used.iter().any(|u| u == shim),
+                    None => has_std,
+                }
+        })
+        .collect())
+}
+
+/// Make `dest` a copy of `rust_src` made of links, with the directories
+/// in `links`, relative to `rust_src`, linking to somewhere else instead.
+pub fn link(rust_src: &Path, dest: &Path, links: &[(PathBuf, PathBuf)]) -> Result<()> {
+    fs::create_dir_all(dest).with_context(|| format!("Couldn't create {}", dest.display()))?;
+    for entry in fs::read_dir(rust_src)? {
+        let entry = entry?;
+        let name = PathBuf::from(entry.file_name());
+        let target = links.iter().find(|(rel, _)| *rel == name);
+        let nested: Vec<_> = links
+            .iter()
+            .filter_map(|(rel, to)| Some((rel.strip_prefix(&name).ok()?.into(), to.clone())))
+            .filter(|(rel, _): &(PathBuf, _)| !rel.as_os_str().is_empty())
+            .collect();
+        if let Some((_, to)) = target {
+            util::symlink_dir(to, &dest.join(&name))?;
+        } else if !nested.is_empty() {
+            link(&entry.path(), &dest.join(&name), &nested)?;
+        } else if entry.file_type()?.is_dir() {
+            util::symlink_dir(&entry.path(), &dest.join(&name))?;
+        } else {
+            util::symlink_file(&entry.path(), &dest.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Read and parse the `Cargo.toml` at `manifest`.
+fn read(manifest: &Path) -> Result<Value> {
+    toml::from_str(
+        &fs::read_to_string(manifest)
+            .with_context(|| format!("Couldn't read {}", manifest.display()))?,
+    )
+    .with_context(|| format!("Couldn't parse {}", manifest.display()))
+}
+
+/// The package name from a parsed `Cargo.toml`.
+fn package_name(manifest: &Value) -> Option<String> {
+    Some(manifest.get("package")?.get("name")?.as_str()?.into())
+}
+
+/// The normal dependencies from a parsed `Cargo.toml`, for every platform.
+///
+/// Build and dev dependencies are never part of the sysroot.
+fn dependencies(manifest: &Value) -> Vec<(&str, &Value)> {
+    let platforms = manifest
+        .get("target")
+        .and_then(Value::as_table)
+        .into_iter()
+        .flat_map(|t| t.values());
+    std::iter::once(manifest)
+        .chain(platforms)
+        .filter_map(|t| t.get("dependencies")?.as_table())
+        .flatten()
+        .map(|(name, dep)| (name.as_str(), dep))
+        .collect()
+}
@@ -7,8 +7,8 @@
 //!
 //! The sysroot is located in `.target/sysroot`
 use anyhow::*;
-use cargo_toml2::{from_path, to_path, Build, CargoConfig, CargoToml};
-use std::{fs, path::Path};
+use serde::Serialize;
+use std::{env, ffi::OsString, fs, path::PathBuf, process};
 use structopt::StructOpt;
 
 mod args;
@@ -17,106 +17,399 @@ mod util;
 use crate::{args::*, util::get_rust_src};
 use cargo_sysroot::*;
 
-/// Create a `.cargo/config` to use our target and sysroot.
-fn generate_cargo_config(target: &Path, sysroot: &Path) -> Result<()> {
-    let cargo = Path::new(".cargo");
-    let cargo_config = cargo.join("config.toml");
-    fs::create_dir_all(cargo)?;
-
-    if cargo_config.exists() {
-        // TODO: Be smarter, update existing. Warn?
-        return Ok(());
-    }
-
-    let target = target
-        // .canonicalize()
-        // .with_context(|| {
-        //     format!(
-        //         "Couldn't get absolute path to custom target: {}",
-        //         target.display()
-        //     )
-        // })?
-        .to_str()
-        .context("Failed to convert target.json path to utf-8")?
-        .to_string();
-    let sysroot_dir = sysroot
-        .canonicalize()
-        .with_context(|| {
-            format!(
-                "Couldn't get canonical path to sysroot: {}",
-                sysroot.display()
-            )
-        })?
-        .to_str()
-        .with_context(|| {
-            format!(
-                "Failed to convert sysroot path to utf-8: {}",
-                sysroot.display()
-            )
-        })?
-        .to_string();
-
-    let config = CargoConfig {
-        build: Some(Build {
-            target: Some(target),
-            rustflags: Some(vec!["--sysroot".to_owned(), sysroot_dir]),
-            ..Default::default()
-        }),
-        ..Default::default()
-    };
-    if !cargo_config.exists() {
-        to_path(&cargo_config, &config).context("Failed writing sysroot Cargo.toml")?;
+fn main() -> Result<()> {
+    // When building with `CrateSettings::rustflags`, this is also the rustc
+    // wrapper.
+    wrap_rustc()?;
+    let Args::Sysroot(mut args) = Args::from_args();
+    match args.cmd.take() {
+        None => build(&args),
+        Some(Command::Clean(mut opts)) => {
+            args.common.merge(opts.common);
+            opts.common = args.common;
+            clean(&opts)
+        }
+        Some(Command::Exec(mut opts)) => {
+            args.common.merge(opts.common);
+            opts.common = args.common;
+            exec(&opts, args.force || opts.force)
+        }
+        Some(Command::Print(mut opts)) => {
+            args.common.merge(opts.common);
+            opts.common = args.common;
+            print(&opts)
+        }
     }
-
-    Ok(())
 }
 
-fn main() -> Result<()> {
-    let Args::Sysroot(mut args) = Args::from_args();
-    let toml: CargoToml =
-        from_path(&args.manifest_path).with_context(|| args.manifest_path.display().to_string())?;
+/// Load the workspace configuration, with the command line arguments
+/// applied.
+fn load_config(args: &Common) -> Result<WorkspaceConfig> {
+    let mut workspace = SysrootConfig::from_workspace(args.manifest_path())?;
 
-    if args.target.is_none() {
-        args.target = Some(
-            toml.package
-                .metadata
-                .context("Missing package metadata")?
-                .get("cargo-sysroot")
-                .context("Missing cargo-sysroot metadata")?
-                .get("target")
-                .context("Missing cargo-sysroot target")?
-                .as_str()
-                .context("Cargo-sysroot target field was not a string")?
-                .into(),
-        );
+    // Command line arguments take priority over the manifest.
+    for (_, config) in &mut workspace.members {
+        if !args.target.is_empty() {
+            config.target = args.target.clone();
+        }
+        if args.crates.is_some() {
+            config.crates = args.crates;
+        }
+        config.features.extend(&args.features);
+        if args.no_default_features {
+            config.default_features = Some(false);
+        }
+        config.std_features.extend(&args.std_features);
+        config
+            .extra_crates
+            .extend(args.extra_crates.iter().cloned());
+        if args.sysroot_dir.is_some() {
+            config.output = args.sysroot_dir.clone();
+        }
+        if args.rust_src_dir.is_some() {
+            config.rust_src = args.rust_src_dir.clone();
+        }
+        if args.lockfile.is_some() {
+            config.lockfile = args.lockfile.clone();
+        }
     }
+    Ok(workspace)
+}
 
-    if args.rust_src_dir.is_none() {
-        args.rust_src_dir = Some(get_rust_src()?)
+/// Builders for every sysroot in `workspace`, with the command line
+/// arguments applied.
+fn builders(args: &Common, workspace: &WorkspaceConfig) -> Result<Vec<SysrootBuilder>> {
+    let mut builders = workspace.builders()?;
+    for sys in &mut builders {
+        if let Some(target_dir) = &args.target_dir {
+            sys.target_dir(target_dir.clone());
+        }
+        if let Some(profile) = &args.profile {
+            sys.profile(profile.as_str());
+        }
+        // See `wrap_rustc` in `main`.
+        sys.rustc_wrapper(env::current_exe().context("Couldn't find cargo-sysroot")?);
+        sys.offline(args.offline)
+            .frozen(args.frozen)
+            .locked(args.locked);
+        if let Some(vendor_dir) = &args.vendor_dir {
+            sys.crate_source(CrateSource::Vendor(vendor_dir.clone()));
+        }
+        if let Some(local_registry) = &args.local_registry {
+            sys.crate_source(CrateSource::LocalRegistry(local_registry.clone()));
+        }
     }
+    Ok(builders)
+}
 
-    args.cargo_profile = toml.profile;
+fn missing_target() -> Error {
+    anyhow!(
+        "Missing cargo-sysroot target, set `package.metadata.cargo-sysroot.target` or pass \
+         `--target`"
+    )
+}
 
-    clean_artifacts(&args.sysroot_dir)?;
-    fs::create_dir_all(&args.sysroot_dir).context("Couldn't create sysroot directory")?;
+/// Builders for every sysroot, ready to build.
+///
+/// Unlike [`builders`], this finds `rust-src` and creates the sysroot
+/// directories.
+fn prepare(args: &Common, force: bool) -> Result<Vec<SysrootBuilder>> {
+    let mut workspace = load_config(args)?;
+    if workspace.members.iter().any(|(_, c)| c.rust_src.is_none()) {
+        let rust_src = get_rust_src()?;
+        for (_, config) in &mut workspace.members {
+            config.rust_src.get_or_insert_with(|| rust_src.clone());
+        }
+    }
 
-    let args = args;
+    let mut builders = builders(args, &workspace)?;
+    if builders.is_empty() {
+        return Err(missing_target());
+    }
+    for sys in &mut builders {
+        sys.force(force);
+        fs::create_dir_all(sys.output_dir()).context("Couldn't create sysroot directory")?;
+    }
+    Ok(builders)
+}
 
+/// Build the sysroot, and configure cargo to use it.
+fn build(args: &args::Sysroot) -> Result<()> {
+    let builders = prepare(&args.common, args.force)?;
     println!("Building sysroot crates");
     if !args.no_config {
-        generate_cargo_config(args.target.as_ref().unwrap(), &args.sysroot_dir)
-            .context("Couldn't create .cargo/config.toml")?;
+        let sysroot_dir = builders[0].output_dir();
+        if builders.iter().any(|b| b.output_dir() != sysroot_dir) {
+            return Err(anyhow!(
+                "Workspace members use different sysroot directories, which can't be \
+                 configured in one .cargo/config.toml. Pass `--no-config`."
+            ));
+        }
+        let mut targets = Vec::new();
+        for target in builders.iter().flat_map(|b| b.target_list()) {
+            if !targets.contains(target) {
+                targets.push(target.clone());
+            }
+        }
+        let config_path = cargo_config_path();
+        // Cargo ignores every `rustflags` in its configuration if these are set.
+        for var in &["CARGO_ENCODED_RUSTFLAGS", "RUSTFLAGS"] {
+            if let Some(flags) = env::var_os(var) {
+                if !flags.to_string_lossy().contains("--sysroot") {
+                    return Err(anyhow!(
+                        "`{}` is set, so cargo will ignore the `--sysroot` in {}. Unset it, \
+                         pass `--no-config` and add `--sysroot {}` to it, or use `cargo \
+                         sysroot exec`",
+                        var,
+                        config_path.display(),
+                        builders[0].sysroot_dir()?.display()
+                    ));
+                }
+                break;
+            }
+        }
+        let changes = update_cargo_config(&config_path, &targets, sysroot_dir)
+            .with_context(|| format!("Couldn't update {}", config_path.display()))?;
+        if !changes.is_empty() {
+            let changes: Vec<_> = changes.iter().map(ToString::to_string).collect();
+            println!("Updated {}: {}", config_path.display(), changes.join(", "));
+        }
+    }
+
+    for sys in &builders {
+        for target in sys.build()?.targets {
+            if target.up_to_date {
+                println!("Sysroot for {} is up to date", target.target.display());
+            }
+        }
     }
 
-    let mut sys = SysrootBuilder::new(cargo_sysroot::Sysroot::Alloc);
-    sys.manifest(args.manifest_path)
-        .output(args.sysroot_dir)
-        .target(args.target.expect("BUG: Missing target triple?"))
-        .features(&[Features::CompilerBuiltinsMem]);
-    if let Some(rust_src) = args.rust_src_dir {
-        sys.rust_src(rust_src);
+    Ok(())
+}
+
+/// Build the sysroot, and run a command configured to use it through the
+/// environment.
+///
+/// Any existing `CARGO_ENCODED_RUSTFLAGS` or `RUSTFLAGS` are kept.
+fn exec(opts: &ExecOpts, force: bool) -> Result<()> {
+    let builders = prepare(&opts.common, force)?;
+    let targets: Vec<_> = builders.iter().flat_map(|b| b.target_list()).collect();
+    let (sys, target) = match (builders.as_slice(), targets.as_slice()) {
+        ([sys], [target]) => (sys, target),
+        _ => {
+            return Err(anyhow!(
+                "`cargo sysroot exec` needs exactly one target, found {}. Pass `--target`.",
+                targets.len()
+            ))
+        }
+    };
+    let sysroot = sys.build()?.sysroot;
+
+    let mut rustflags: Vec<OsString> = match env::var_os("CARGO_ENCODED_RUSTFLAGS") {
+        Some(flags) if flags.is_empty() => Vec::new(),
+        Some(flags) => flags
+            .to_str()
+            .context("CARGO_ENCODED_RUSTFLAGS was not valid utf-8")?
+            .split('\x1f')
+            .map(Into::into)
+            .collect(),
+        None => env::var("RUSTFLAGS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(Into::into)
+            .collect(),
+    };
+    rustflags.push("--sysroot".into());
+    rustflags.push(sysroot.into());
+    let rustflags = rustflags
+        .iter()
+        .map(|f| f.to_str().context("rustflags were not valid utf-8"))
+        .collect::<Result<Vec<_>>>()?
+        .join("\x1f");
+
+    let (program, args) = opts.command.split_first().context("Missing command")?;
+    let status = process::Command::new(program)
+        .args(args)
+        .env("CARGO_BUILD_TARGET", target)
+        .env("CARGO_ENCODED_RUSTFLAGS", rustflags)
+        .status()
+        .with_context(|| format!("Couldn't run {}", program.to_string_lossy()))?;
+    process::exit(status.code().unwrap_or(1));
+}
+
+/// The sysroot for one [`SysrootBuilder`], as printed by [`print`].
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PrintSysroot {
+    sysroot: PathBuf,
+    build_dir: PathBuf,
+    rustflags: Vec<String>,
+
+    /// The `[profile]` table, see [`SysrootBuilder::profiles`].
+    profile: toml::value::Table,
+
+    targets: Vec<PrintTarget>,
+}
+
+/// One target in [`PrintSysroot`].
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PrintTarget {
+    /// The target as configured, a triple or path to a JSON specification.
+    target: PathBuf,
+
+    /// The name rustc uses for `target`.
+    name: String,
+
+    target_libdir: PathBuf,
+}
+
+/// Print information about the sysroots, without building them.
+fn print(opts: &PrintOpts) -> Result<()> {
+    let workspace = load_config(&opts.common)?;
+    let builders = builders(&opts.common, &workspace)?;
+    if builders.is_empty() {
+        return Err(missing_target());
+    }
+
+    let mut sysroots = Vec::with_capacity(builders.len());
+    for sys in &builders {
+        let rustflags = sys
+            .sysroot_rustflags()?
+            .into_iter()
+            .map(|f| {
+                f.into_string()
+                    .map_err(|_| anyhow!("Sysroot path was not valid utf-8"))
+            })
+            .collect::<Result<_>>()?;
+        let mut targets = Vec::with_capacity(sys.target_list().len());
+        for target in sys.target_list() {
+            targets.push(PrintTarget {
+                target: target.clone(),
+                name: target_name(target)?.to_string_lossy().into(),
+                target_libdir: sys.target_libdir(target)?,
+            });
+        }
+        sysroots.push(PrintSysroot {
+            sysroot: sys.sysroot_dir()?,
+            build_dir: util::absolute(&sys.build_dir())?,
+            rustflags,
+            profile: sys.profiles()?,
+            targets,
+        });
+    }
+
+    match opts.format {
+        Format::Json => {
+            let json = match opts.what {
+                Print::Sysroot => {
+                    serde_json::to_string(&sysroots.iter().map(|s| &s.sysroot).collect::<Vec<_>>())
+                }
+                Print::TargetLibdir => serde_json::to_string(
+                    &sysroots
+                        .iter()
+                        .flat_map(|s| &s.targets)
+                        .map(|t| &t.target_libdir)
+                        .collect::<Vec<_>>(),
+                ),
+                Print::Rustflags => serde_json::to_string(
+                    &sysroots.iter().map(|s| &s.rustflags).collect::<Vec<_>>(),
+                ),
+                Print::Profile => {
+                    serde_json::to_string(&sysroots.iter().map(|s| &s.profile).collect::<Vec<_>>())
+                }
+                Print::All => serde_json::to_string_pretty(&sysroots),
+            }?;
+            println!("{}", json);
+        }
+        Format::Text => {
+            for sys in &sysroots {
+                match opts.what {
+                    Print::Sysroot => println!("{}", sys.sysroot.display()),
+                    Print::TargetLibdir => {
+                        for target in &sys.targets {
+                            println!("{}", target.target_libdir.display());
+                        }
+                    }
+                    Print::Rustflags => println!("{}", sys.rustflags.join(" ")),
+                    Print::Profile => print!("{}", profile_toml(&sys.profile)?),
+                    Print::All => {
+                        println!("sysroot: {}", sys.sysroot.display());
+                        println!("build-dir: {}", sys.build_dir.display());
+                        println!("rustflags: {}", sys.rustflags.join(" "));
+                        println!("profile:");
+                        for line in profile_toml(&sys.profile)?.lines() {
+                            println!("    {}", line);
+                        }
+                        for target in &sys.targets {
+                            println!(
+                                "target-libdir ({}): {}",
+                                target.name,
+                                target.target_libdir.display()
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `profile` as the `[profile]` table of a `Cargo.toml`.
+fn profile_toml(profile: &toml::value::Table) -> Result<String> {
+    let mut toml = toml::value::Table::new();
+    toml.insert("profile".into(), profile.clone().into());
+    toml::to_string(&toml).context("Couldn't format profile")
+}
+
+/// Remove the sysroot, or parts of it.
+fn clean(opts: &CleanOpts) -> Result<()> {
+    let args = &opts.common;
+    let workspace = load_config(args)?;
+    let mut builders = builders(args, &workspace)?;
+    if builders.is_empty() {
+        // Nothing configured, but there may still be something at the defaults.
+        let mut sys = SysrootBuilder::new(cargo_sysroot::Sysroot::Alloc);
+        if let Some(sysroot_dir) = &args.sysroot_dir {
+            sys.output(sysroot_dir.clone());
+        }
+        if let Some(target_dir) = &args.target_dir {
+            sys.target_dir(target_dir.clone());
+        }
+        builders.push(sys);
+    }
+
+    let mut what: Vec<_> = args.target.iter().cloned().map(Clean::Target).collect();
+    if opts.build_dir {
+        what.push(Clean::BuildDir);
+    }
+    if opts.host_tools {
+        what.push(Clean::HostTools);
+    }
+    if what.is_empty() {
+        what.push(Clean::All);
+    }
+
+    let mut removed = Vec::new();
+    for sys in &builders {
+        for clean in &what {
+            for path in sys.clean(clean, opts.dry_run)? {
+                if !removed.contains(&path) {
+                    if opts.dry_run {
+                        println!("Would remove {}", path.display());
+                    } else {
+                        println!("Removed {}", path.display());
+                    }
+                    removed.push(path);
+                }
+            }
+        }
+    }
+    if removed.is_empty() {
+        println!("Nothing to clean");
     }
-    sys.build()?;
 
     Ok(())
 }
@@ -0,0 +1,122 @@
+//! Watching the progress of [`crate::SysrootBuilder::build`].
+use crate::CrateOverride;
+use std::{fmt, path::Path, sync::Arc};
+
+/// Something that happened during [`crate::SysrootBuilder::build`].
+///
+/// Events for each phase are sent when the phase starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildEvent<'a> {
+    /// Another process is building the sysroot, so this one is waiting for
+    /// it to finish, by waiting for the lock file at this path.
+    WaitingForLock(&'a Path),
+
+    /// The rust sources were found.
+    RustSrc(&'a Path),
+
+    /// The `Cargo.toml` used to build the sysroot crates is being generated.
+    GenerateManifest(&'a Path),
+
+    /// The crate `name` will be built from `source`.
+    ///
+    /// See [`crate::SysrootBuilder::override_crate`].
+    Override {
+        name: &'a str,
+        source: &'a CrateOverride,
+    },
+
+    /// The sysroot for `target` is up to date, and won't be built.
+    UpToDate { target: &'a Path },
+
+    /// The sysroot for `target` is being built.
+    Building { target: &'a Path },
+
+    /// Crate `current` of `total` was compiled for `target`.
+    ///
+    /// `current` starts at 1. If the crate didn't need to be recompiled,
+    /// `fresh` is `true`.
+    Compiled {
+        target: &'a Path,
+        name: &'a str,
+        version: &'a str,
+        fresh: bool,
+        current: usize,
+        total: usize,
+    },
+
+    /// Output from cargo or rustc, without a trailing newline.
+    ///
+    /// This is a line of output, or a whole rendered diagnostic.
+    CargoOutput(&'a str),
+
+    /// The compiled crates for `target` are being copied into the sysroot.
+    CopyArtifacts { target: &'a Path },
+
+    /// The host tools are being copied into the sysroot.
+    CopyHostTools,
+}
+
+/// Watches the progress of [`crate::SysrootBuilder::build`].
+///
+/// This is implemented for closures taking a [`BuildEvent`].
+///
+/// See [`crate::SysrootBuilder::observer`].
+pub trait BuildObserver: Send + Sync {
+    /// Called for every [`BuildEvent`].
+    fn event(&self, event: &BuildEvent<'_>);
+}
+
+impl<F> BuildObserver for F
+where
+    F: Fn(&BuildEvent<'_>) + Send + Sync,
+{
+    fn event(&self, event: &BuildEvent<'_>) {
+        self(event)
+    }
+}
+
+/// The default [`BuildObserver`].
+///
+/// Passes cargo output through to stderr, notes when waiting for another
+/// process and any crate overrides, and ignores everything else.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrintObserver;
+
+impl BuildObserver for PrintObserver {
+    fn event(&self, event: &BuildEvent<'_>) {
+        match event {
+            BuildEvent::CargoOutput(output) => eprintln!("{}", output),
+            BuildEvent::WaitingForLock(path) => {
+                eprintln!("Blocking waiting for file lock on {}", path.display())
+            }
+            BuildEvent::Override { name, source } => {
+                eprintln!("Overriding `{}` with {}", name, source)
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A shared [`BuildObserver`], so [`crate::SysrootBuilder`] can be `Debug`
+/// and `Clone`.
+#[derive(Clone)]
+pub(crate) struct Observer(pub Arc<dyn BuildObserver>);
+
+impl Observer {
+    pub fn event(&self, event: BuildEvent<'_>) {
+        self.0.event(&event)
+    }
+}
+
+impl Default for Observer {
+    fn default() -> Self {
+        Self(Arc::new(PrintObserver))
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
@@ -0,0 +1,136 @@
+//! What happened during [`crate::SysrootBuilder::build`].
+use crate::CrateOverride;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// Report returned by [`crate::SysrootBuilder::build`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BuildReport {
+    /// Path to the sysroot, suitable to pass to rustc.
+    pub sysroot: PathBuf,
+
+    /// What happened for each target, in the order they were added.
+    pub targets: Vec<TargetReport>,
+
+    /// Crates built from somewhere else, by name.
+    ///
+    /// See [`crate::SysrootBuilder::override_crate`].
+    pub overrides: BTreeMap<String, CrateOverride>,
+
+    /// The `[profile]` table the crates were built with.
+    ///
+    /// See [`crate::SysrootBuilder::profiles`].
+    pub profiles: toml::value::Table,
+}
+
+/// What happened when building the sysroot for one target.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TargetReport {
+    /// The target, as passed to [`crate::SysrootBuilder::target`].
+    pub target: PathBuf,
+
+    /// Whether the sysroot was already up to date, and nothing was built.
+    ///
+    /// If so, everything else is empty.
+    pub up_to_date: bool,
+
+    /// The crates that went into the sysroot.
+    pub crates: Vec<CrateReport>,
+
+    /// The artifacts copied into the sysroot.
+    pub artifacts: Vec<PathBuf>,
+
+    /// Warnings from compiling the crates, as rendered by rustc.
+    pub warnings: Vec<String>,
+}
+
+impl TargetReport {
+    pub(crate) fn new(target: PathBuf, up_to_date: bool) -> Self {
+        Self {
+            target,
+            up_to_date,
+            crates: Vec::new(),
+            artifacts: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// A crate compiled for the sysroot.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CrateReport {
+    /// Package name.
+    pub name: String,
+
+    /// Package version.
+    pub version: String,
+
+    /// Whether cargo considered the crate fresh, and didn't recompile it.
+    pub fresh: bool,
+}
+
+/// A JSON message from `cargo --message-format=json`.
+///
+/// Only the parts we need.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub(crate) enum CargoMessage {
+    CompilerArtifact(Artifact),
+    CompilerMessage {
+        message: Diagnostic,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Artifact {
+    pub package_id: String,
+    pub target: ArtifactTarget,
+    pub filenames: Vec<PathBuf>,
+    pub fresh: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ArtifactTarget {
+    pub kind: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Diagnostic {
+    pub level: String,
+    pub rendered: Option<String>,
+}
+
+impl Artifact {
+    /// Package name and version from the package id.
+    ///
+    /// Cargo has used two formats for these, `name version (source)`
+    /// and `source#name@version`, where the name may be left out if it's
+    /// the last part of the source path.
+    pub fn name_version(&self) -> (String, String) {
+        let id = self.package_id.as_str();
+        match id.rsplit_once('#') {
+            Some((source, fragment)) => match fragment.split_once('@') {
+                Some((name, version)) => (name.into(), version.into()),
+                None => {
+                    let name = source
+                        .trim_end_matches('/')
+                        .rsplit('/')
+                        .next()
+                        .unwrap_or_default();
+                    (name.into(), fragment.into())
+                }
+            },
+            None => {
+                let mut parts = id.split_whitespace();
+                let name = parts.next().unwrap_or_default();
+                let version = parts.next().unwrap_or_default();
+                (name.into(), version.into())
+            }
+        }
+    }
+}
@@ -1,11 +1,118 @@
 //! Utility.
+use crate::is_target_spec;
 use anyhow::{anyhow, Context, Result};
 use fs_extra::dir::{copy, CopyOptions};
+use serde::Deserialize;
 use std::{
+    env,
+    ffi::{OsStr, OsString},
     fs,
-    path::{Path, PathBuf},
-    process::Command,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
 };
+use toml::{value::Table, Value};
+
+/// Get the `[profile]` section of the `Cargo.toml` at `manifest`.
+///
+/// Unlike [`cargo_toml2::CargoToml`], this works for virtual manifests,
+/// and keeps custom profiles and every setting, even those
+/// [`cargo_toml2::Profile`] doesn't know about.
+pub fn get_manifest_profile(manifest: &Path) -> Result<Option<Table>> {
+    #[derive(Deserialize)]
+    struct Manifest {
+        profile: Option<Table>,
+    }
+
+    let toml: Manifest = toml::from_str(
+        &fs::read_to_string(manifest)
+            .with_context(|| format!("Couldn't read {}", manifest.display()))?,
+    )
+    .with_context(|| format!("Couldn't parse profiles from {}", manifest.display()))?;
+    Ok(toml.profile)
+}
+
+/// Merge `over` into `base`, replacing everything but tables, which are
+/// merged the same way.
+pub fn merge_toml(base: &mut Table, over: Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(over)) => merge_toml(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Get the names of the `[features]` in the `Cargo.toml` at `manifest`.
+pub fn get_manifest_features(manifest: &Path) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Manifest {
+        #[serde(default)]
+        features: Table,
+    }
+
+    let toml: Manifest = toml::from_str(
+        &fs::read_to_string(manifest)
+            .with_context(|| format!("Couldn't read {}", manifest.display()))?,
+    )
+    .with_context(|| format!("Couldn't parse features from {}", manifest.display()))?;
+    Ok(toml.features.into_iter().map(|(k, _)| k).collect())
+}
+
+/// Get the `panic-strategy` of `target`, a target triple or JSON
+/// specification.
+///
+/// Targets without one unwind.
+pub fn get_panic_strategy(target: &Path) -> Result<String> {
+    #[derive(Deserialize)]
+    struct Spec {
+        #[serde(rename = "panic-strategy")]
+        panic_strategy: Option<String>,
+    }
+
+    let spec = if is_target_spec(target) {
+        fs::read(target).with_context(|| format!("Couldn't read {}", target.display()))?
+    } else {
+        let rustc = Command::new("rustc")
+            .arg("-Z")
+            .arg("unstable-options")
+            .arg("--print")
+            .arg("target-spec-json")
+            .arg("--target")
+            .arg(target)
+            .output()?;
+        if !rustc.status.success() {
+            return Err(anyhow!(
+                "Failed to get target specification: {}",
+                String::from_utf8_lossy(&rustc.stderr).trim()
+            ));
+        }
+        rustc.stdout
+    };
+    let spec: Spec = serde_json::from_slice(&spec).context("Invalid target specification")?;
+    Ok(spec.panic_strategy.unwrap_or_else(|| "unwind".into()))
+}
+
+/// The panic strategy set by `-C panic=<strategy>` in `flags`, if any.
+///
+/// The last one wins, like rustc.
+pub fn panic_flag(flags: &[OsString]) -> Option<String> {
+    let mut strategy = None;
+    let mut flags = flags.iter().filter_map(|f| f.to_str());
+    while let Some(flag) = flags.next() {
+        let codegen = match flag {
+            "-C" | "--codegen" => flags.next(),
+            flag => flag
+                .strip_prefix("-C")
+                .or_else(|| flag.strip_prefix("--codegen=")),
+        };
+        if let Some(s) = codegen.and_then(|c| c.strip_prefix("panic=")) {
+            strategy = Some(s.to_string());
+        }
+    }
+    strategy
+}
 
 /// Get the configured rustc sysroot.
 /// This is the HOST sysroot.
@@ -22,6 +129,185 @@ fn get_rustc_sysroot() -> Result<PathBuf> {
     Ok(sysroot)
 }
 
+/// Get the verbose version info of the configured rustc.
+///
+/// This includes the release and commit hash.
+pub fn get_rustc_version() -> Result<String> {
+    let rustc = Command::new("rustc").arg("-vV").output()?;
+    if !rustc.status.success() {
+        return Err(anyhow!("Failed to get rustc version"));
+    }
+    String::from_utf8(rustc.stdout).context("rustc version was not utf-8")
+}
+
+/// Count the crates that will be compiled for `target` when building
+/// `manifest` with `rustflags`, not counting `manifest` itself, or build
+/// dependencies, which are compiled for the host.
+///
+/// `cargo_args` are passed to cargo.
+///
+/// # Errors
+///
+/// - If cargo can't resolve the dependencies, naming any missing crates.
+pub fn count_crates(
+    manifest: &Path,
+    target: &Path,
+    rustflags: &OsStr,
+    cargo_args: &[OsString],
+) -> Result<usize> {
+    #[derive(Deserialize)]
+    struct Metadata {
+        resolve: Resolve,
+    }
+    #[derive(Deserialize)]
+    struct Resolve {
+        root: String,
+        nodes: Vec<Node>,
+    }
+    #[derive(Deserialize)]
+    struct Node {
+        id: String,
+        deps: Vec<NodeDep>,
+    }
+    #[derive(Deserialize)]
+    struct NodeDep {
+        pkg: String,
+        dep_kinds: Vec<DepKind>,
+    }
+    #[derive(Deserialize)]
+    struct DepKind {
+        kind: Option<String>,
+    }
+    let out = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+        .arg("metadata")
+        .arg("--format-version")
+        .arg("1")
+        .arg("--filter-platform")
+        .arg(target)
+        .arg("--manifest-path")
+        .arg(manifest)
+        .args(cargo_args)
+        .env("RUSTFLAGS", rustflags)
+        .output()
+        .context("Couldn't find/run cargo command")?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        let missing = missing_crates(&stderr);
+        let err = anyhow!("{}", stderr.trim_end());
+        if !missing.is_empty() {
+            return Err(err.context(format!(
+                "Missing crates needed to build the sysroot: {}",
+                missing.join(", ")
+            )));
+        }
+        return Err(err.context(format!("Couldn't get metadata for {}", manifest.display())));
+    }
+    let metadata: Metadata =
+        serde_json::from_slice(&out.stdout).context("Couldn't parse `cargo metadata` output")?;
+
+    // Only normal dependencies are built for the target, build dependencies
+    // are built for the host.
+    let resolve = metadata.resolve;
+    let mut seen = vec![resolve.root.as_str()];
+    let mut todo = vec![resolve.root.as_str()];
+    while let Some(id) = todo.pop() {
+        let node = resolve
+            .nodes
+            .iter()
+            .find(|n| n.id == id)
+            .context("Couldn't parse `cargo metadata` output")?;
+        for dep in &node.deps {
+            let normal = dep.dep_kinds.iter().any(|k| k.kind.is_none());
+            if normal && !seen.contains(&dep.pkg.as_str()) {
+                seen.push(&dep.pkg);
+                todo.push(&dep.pkg);
+            }
+        }
+    }
+    Ok(seen.len() - 1)
+}
+
+/// The crates cargo couldn't find, from its error output.
+fn missing_crates(stderr: &str) -> Vec<String> {
+    const PATTERNS: &[&str] = &[
+        "no matching package named `",
+        "failed to select a version for the requirement `",
+        "failed to download `",
+    ];
+    let mut missing = Vec::new();
+    for pattern in PATTERNS {
+        for (i, _) in stderr.match_indices(pattern) {
+            let rest = &stderr[i + pattern.len()..];
+            let name = rest.split(['`', ' ']).next().unwrap_or_default();
+            if !name.is_empty() && !missing.iter().any(|m| m == name) {
+                missing.push(name.to_string());
+            }
+        }
+    }
+    missing
+}
+
+/// Check there's a C compiler for `target`, looking for it like the `cc`
+/// crate does, in `env` and then the environment.
+pub fn check_c_compiler(target: &OsStr, env: &[(String, OsString)]) -> Result<()> {
+    let target = target.to_str().context("Target name was not valid utf-8")?;
+    let vars = [
+        format!("CC_{}", target),
+        format!("CC_{}", target.replace('-', "_")),
+        "TARGET_CC".into(),
+        "CC".into(),
+    ];
+    let cc = vars
+        .iter()
+        .find_map(|var| {
+            env.iter()
+                .find(|(k, _)| k == var)
+                .map(|(_, v)| v.clone())
+                .or_else(|| env::var_os(var))
+        })
+        .unwrap_or_else(|| "cc".into());
+    let found = Command::new(&cc)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok();
+    if !found {
+        return Err(anyhow!(
+            "No C compiler for `{}`, which the `c` feature needs. Tried `{}`. Set `cc` in \
+             `package.metadata.cargo-sysroot.c-toolchain.{}`, or `CC_{}`",
+            target,
+            cc.to_string_lossy(),
+            target,
+            target.replace('-', "_")
+        ));
+    }
+    Ok(())
+}
+
+/// Get the `cfg` values rustc sets for `target`, as printed by
+/// `rustc --print cfg`, such as `unix` or `target_os="linux"`.
+#[cfg(feature = "toml_edit")]
+pub fn get_target_cfg(target: &Path) -> Result<Vec<String>> {
+    let rustc = Command::new("rustc")
+        .arg("--print")
+        .arg("cfg")
+        .arg("--target")
+        .arg(target)
+        .output()?;
+    if !rustc.status.success() {
+        return Err(anyhow!(
+            "rustc --print cfg failed: {}",
+            String::from_utf8_lossy(&rustc.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8(rustc.stdout)
+        .context("rustc cfg was not utf-8")?
+        .lines()
+        .map(Into::into)
+        .collect())
+}
+
 /// Get the configured rustc sysroot lib dir for `target`.
 fn get_rustc_target_libdir(target: Option<&Path>) -> Result<PathBuf> {
     let mut rustc = Command::new("rustc");
@@ -65,20 +351,49 @@ pub fn get_rust_src() -> Result<PathBuf> {
     Ok(sys)
 }
 
-/// Host tools such as rust-lld need to be in the sysroot to link correctly.
-/// Copies entire host target, so stuff like tests work.
-#[allow(clippy::blocks_in_if_conditions)]
-pub fn copy_host_tools(local_sysroot: &Path) -> Result<()> {
+/// The canonical, absolute, form of `path`, which doesn't have to exist.
+///
+/// If it doesn't, it's made absolute relative to the current directory.
+pub fn absolute(path: &Path) -> Result<PathBuf> {
+    match path.canonicalize() {
+        Ok(path) => Ok(path),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(env::current_dir()?
+            .join(path)
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .collect()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Get the host target triple of the configured rustc.
+pub fn get_host_target() -> Result<String> {
     let root = get_rustc_target_libdir(None)?;
-    let host = root
+    Ok(root
         .parent()
         .and_then(|f| f.file_stem())
         .and_then(|f| f.to_str())
-        .context("Error parsing host target triple")?;
+        .context("Error parsing host target triple")?
+        .into())
+}
+
+/// Host tools such as rust-lld need to be in the sysroot to link correctly.
+/// Copies entire host target, so stuff like tests work.
+///
+/// This is the `lib/rustlib/<host>` directory, with its `lib` and `bin`.
+#[allow(clippy::blocks_in_conditions)]
+pub fn copy_host_tools(local_sysroot: &Path) -> Result<()> {
+    let root = get_rustc_target_libdir(None)?;
+    let host = get_host_target()?;
+    // Copied here first, so an interrupted copy can't look up to date.
+    let staging = local_sysroot.join(".staging");
     let local_sysroot = local_sysroot.join("lib").join("rustlib").join(&host);
-    let src = root;
+    recover_dir(&local_sysroot)?;
+    let src = root
+        .parent()
+        .context("Error getting host sysroot directory")?;
 
-    let src_meta = fs::metadata(&src)
+    let src_meta = fs::metadata(src)
         .with_context(|| format!("Couldn't get metadata for {}", src.display()))?;
     let to_meta = fs::metadata(&local_sysroot)
         .with_context(|| format!("Couldn't get metadata for {}", local_sysroot.display()));
@@ -102,17 +417,87 @@ pub fn copy_host_tools(local_sysroot: &Path) -> Result<()> {
             return Ok(());
         }
     }
+    remove_dir(&staging.join(&host))?;
+    fs::create_dir_all(&staging)
+        .with_context(|| format!("Couldn't create {}", staging.display()))?;
     let mut options = CopyOptions::new();
     options.overwrite = true;
-    let local_sysroot = local_sysroot
-        .parent()
-        .context("Error getting local sysroot parent directory")?;
-    copy(&src, &local_sysroot, &options).with_context(|| {
+    copy(src, &staging, &options).with_context(|| {
         format!(
             "Couldn't copy from `{}` to `{}`",
             src.display(),
-            local_sysroot.display()
+            staging.display()
         )
     })?;
+    replace_dir(&staging.join(&host), &local_sysroot)
+}
+
+/// Where the directory `dest` is moved while [`replace_dir`] replaces it.
+fn old_dir(dest: &Path) -> Result<PathBuf> {
+    let mut name = dest
+        .file_name()
+        .context("Invalid directory")?
+        .to_os_string();
+    name.push(".old");
+    Ok(dest.with_file_name(name))
+}
+
+/// Remove `dir` and everything in it, if it exists.
+fn remove_dir(dir: &Path) -> Result<()> {
+    match remove_dir_all::remove_dir_all(dir) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("Couldn't remove {}", dir.display())),
+    }
+}
+
+/// Replace the directory `dest` with the directory `staging`.
+///
+/// `dest` is moved aside before `staging` is moved into place, so there's
+/// only a moment between two renames where it doesn't exist.
+/// If interrupted during that, [`recover_dir`] restores the old `dest`.
+///
+/// `staging` and `dest` must be on the same filesystem.
+pub fn replace_dir(staging: &Path, dest: &Path) -> Result<()> {
+    let old = old_dir(dest)?;
+    remove_dir(&old)?;
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Couldn't create {}", parent.display()))?;
+    }
+    match fs::rename(dest, &old) {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e).with_context(|| format!("Couldn't move {}", dest.display())),
+    }
+    fs::rename(staging, dest)
+        .with_context(|| format!("Couldn't move {} to {}", staging.display(), dest.display()))?;
+    remove_dir(&old)
+}
+
+/// Restore `dest` if [`replace_dir`] was interrupted while replacing it.
+pub fn recover_dir(dest: &Path) -> Result<()> {
+    let old = old_dir(dest)?;
+    if !dest.exists() && old.exists() {
+        fs::rename(&old, dest).with_context(|| format!("Couldn't restore {}", dest.display()))?;
+    }
     Ok(())
 }
+
+/// Create a symbolic link at `link` to the directory `to`.
+pub fn symlink_dir(to: &Path, link: &Path) -> Result<()> {
+    #[cfg(unix)]
+    let res = std::os::unix::fs::symlink(to, link);
+    #[cfg(windows)]
+    let res = std::os::windows::fs::symlink_dir(to, link);
+    res.with_context(|| format!("Couldn't link {} to {}", link.display(), to.display()))
+}
+
+/// Create a symbolic link at `link` to the file `to`.
+pub fn symlink_file(to: &Path, link: &Path) -> Result<()> {
+    #[cfg(unix)]
+    let res = std::os::unix::fs::symlink(to, link);
+    #[cfg(windows)]
+    let res = std::os::windows::fs::symlink_file(to, link);
+    res.with_context(|| format!("Couldn't link {} to {}", link.display(), to.display()))
+}
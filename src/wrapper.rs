@@ -0,0 +1,53 @@
+//! The rustc wrapper for [`crate::CrateSettings::rustflags`].
+use anyhow::{Context, Result};
+use std::{collections::BTreeMap, env, ffi::OsString, process::Command};
+
+/// Flags for each crate, by crate name, as JSON.
+pub(crate) const RUSTFLAGS_VAR: &str = "CARGO_SYSROOT_CRATE_RUSTFLAGS";
+
+/// Any `RUSTC_WRAPPER` that was replaced, to run in turn.
+pub(crate) const INNER_WRAPPER_VAR: &str = "CARGO_SYSROOT_INNER_WRAPPER";
+
+/// Act as the rustc wrapper for [`crate::CrateSettings::rustflags`], if
+/// this process was started as one by [`crate::SysrootBuilder::build`].
+///
+/// If it was, this runs rustc with the flags for the crate being compiled,
+/// and exits with its status. Otherwise this returns immediately.
+///
+/// The program passed to [`crate::SysrootBuilder::rustc_wrapper`] must
+/// call this at the start of `main`.
+///
+/// # Errors
+///
+/// - If started as the wrapper, but rustc couldn't be run.
+pub fn wrap_rustc() -> Result<()> {
+    let flags = match env::var(RUSTFLAGS_VAR) {
+        Ok(flags) => flags,
+        Err(_) => return Ok(()),
+    };
+    let flags: BTreeMap<String, Vec<String>> =
+        serde_json::from_str(&flags).with_context(|| format!("Invalid {}", RUSTFLAGS_VAR))?;
+
+    // Cargo runs `<wrapper> <rustc> <args>...`
+    let mut args: Vec<OsString> = env::args_os().skip(1).collect();
+    let crate_name = args
+        .iter()
+        .position(|a| a == "--crate-name")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|n| n.to_str())
+        .map(String::from);
+    if let Some(extra) = crate_name.and_then(|n| flags.get(&n)) {
+        args.extend(extra.iter().map(Into::into));
+    }
+    let mut rustc = match env::var_os(INNER_WRAPPER_VAR) {
+        Some(inner) => Command::new(inner),
+        None => Command::new(args.remove(0)),
+    };
+    let status = rustc
+        .args(args)
+        .env_remove(RUSTFLAGS_VAR)
+        .env_remove(INNER_WRAPPER_VAR)
+        .status()
+        .context("Couldn't run rustc")?;
+    std::process::exit(status.code().unwrap_or(1));
+}
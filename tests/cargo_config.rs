@@ -0,0 +1,157 @@
+use anyhow::Result;
+use cargo_sysroot::{update_cargo_config, ConfigChange};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Run [`update_cargo_config`] in `dir` on a config containing `existing`,
+/// if any, for `target`, returning the changes, the new config, and the
+/// sysroot.
+fn update_in(
+    dir: &Path,
+    existing: Option<&str>,
+    target: &str,
+) -> Result<(Vec<ConfigChange>, String, String)> {
+    let sysroot = dir.join("sysroot");
+    fs::create_dir_all(&sysroot)?;
+    let sysroot = sysroot.canonicalize()?.to_str().unwrap().to_string();
+    let config = dir.join(".cargo").join("config.toml");
+    if let Some(existing) = existing {
+        fs::create_dir_all(dir.join(".cargo"))?;
+        fs::write(&config, existing)?;
+    }
+    let changes = update_cargo_config(&config, &[PathBuf::from(target)], sysroot.as_ref())?;
+    Ok((changes, fs::read_to_string(&config)?, sysroot))
+}
+
+/// [`update_in`] a new directory.
+fn update(existing: Option<&str>, target: &str) -> Result<(Vec<ConfigChange>, String, String)> {
+    update_in(tempfile::tempdir()?.path(), existing, target)
+}
+
+/// Parse `config`, and get the `rustflags` at `path` as a list.
+fn rustflags(config: &str, path: &[&str]) -> Vec<String> {
+    let mut value: &toml::Value = &toml::from_str(config).unwrap();
+    for key in path {
+        value = &value[*key];
+    }
+    match value {
+        toml::Value::String(s) => s.split_whitespace().map(Into::into).collect(),
+        value => value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap().into())
+            .collect(),
+    }
+}
+
+/// Test that a new config gets the target and sysroot.
+#[test]
+fn fresh() -> Result<()> {
+    let (changes, config, sysroot) = update(None, "x86_64-unknown-none")?;
+    assert_eq!(
+        changes,
+        vec![
+            ConfigChange::Added("build.target".into()),
+            ConfigChange::Added("build.rustflags".into())
+        ]
+    );
+    assert_eq!(
+        rustflags(&config, &["build", "rustflags"]),
+        vec!["--sysroot", &sysroot]
+    );
+    Ok(())
+}
+
+/// Test that existing flags, stale `--sysroot` flags, and comments are
+/// handled, and an up to date config is left alone.
+#[test]
+fn existing_rustflags() -> Result<()> {
+    let existing = r#"
+# My config
+[build]
+target = "x86_64-unknown-none"
+rustflags = ["-Cdebuginfo=2", "--sysroot", "/old/sysroot", "--sysroot=/older"]
+"#;
+    let dir = tempfile::tempdir()?;
+    let (changes, config, sysroot) = update_in(dir.path(), Some(existing), "x86_64-unknown-none")?;
+    assert_eq!(
+        changes,
+        vec![ConfigChange::Updated("build.rustflags".into())]
+    );
+    assert!(config.contains("# My config"));
+    assert_eq!(
+        rustflags(&config, &["build", "rustflags"]),
+        vec!["-Cdebuginfo=2", "--sysroot", &sysroot]
+    );
+
+    let (changes, _, _) = update_in(dir.path(), None, "x86_64-unknown-none")?;
+    assert!(changes.is_empty());
+    Ok(())
+}
+
+/// Test that `rustflags` can also be a string, but not anything else.
+#[test]
+fn string_rustflags() -> Result<()> {
+    let existing = "[build]\nrustflags = \"-Cdebuginfo=2 --sysroot /old\"\n";
+    let (_, config, sysroot) = update(Some(existing), "x86_64-unknown-none")?;
+    assert_eq!(
+        rustflags(&config, &["build", "rustflags"]),
+        vec!["-Cdebuginfo=2", "--sysroot", &sysroot]
+    );
+
+    let err = update(Some("[build]\nrustflags = 1\n"), "x86_64-unknown-none").unwrap_err();
+    assert!(format!("{:#}", err).contains("`build.rustflags` was not a string"));
+    Ok(())
+}
+
+/// Test that the flag goes in the `[target]` tables cargo uses instead of
+/// `build.rustflags`, and only one of them.
+#[test]
+fn target_rustflags() -> Result<()> {
+    let existing = r#"
+[target.x86_64-unknown-linux-gnu]
+rustflags = ["-Ctarget-cpu=native"]
+
+[target.'cfg(target_os = "linux")']
+rustflags = ["-Cdebuginfo=2", "--sysroot", "/old"]
+
+[target.'cfg(windows)']
+rustflags = ["--sysroot", "/windows"]
+"#;
+    let (changes, config, sysroot) = update(Some(existing), "x86_64-unknown-linux-gnu")?;
+    assert!(changes.contains(&ConfigChange::Updated(
+        "target.x86_64-unknown-linux-gnu.rustflags".into()
+    )));
+    assert_eq!(
+        rustflags(
+            &config,
+            &["target", "x86_64-unknown-linux-gnu", "rustflags"]
+        ),
+        vec!["-Ctarget-cpu=native", "--sysroot", &sysroot]
+    );
+    assert_eq!(
+        rustflags(
+            &config,
+            &["target", "cfg(target_os = \"linux\")", "rustflags"]
+        ),
+        vec!["-Cdebuginfo=2"]
+    );
+    assert_eq!(
+        rustflags(&config, &["target", "cfg(windows)", "rustflags"]),
+        vec!["--sysroot", "/windows"]
+    );
+
+    let existing = "[target.'cfg(all(unix, not(windows)))']\nrustflags = []\n";
+    let (_, config, sysroot) = update(Some(existing), "x86_64-unknown-linux-gnu")?;
+    assert_eq!(
+        rustflags(
+            &config,
+            &["target", "cfg(all(unix, not(windows)))", "rustflags"]
+        ),
+        vec!["--sysroot", &sysroot]
+    );
+    Ok(())
+}
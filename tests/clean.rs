@@ -0,0 +1,54 @@
+use anyhow::Result;
+use cargo_sysroot::{Clean, Sysroot, SysrootBuilder};
+use std::{fs, path::PathBuf};
+
+/// Test what each [`Clean`] would remove, and that a dry run removes nothing.
+#[test]
+fn dry_run() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let output = dir.path().join("sysroot");
+    let rustlib = output.join("lib").join("rustlib");
+    let host = "x86_64-unknown-linux-gnu";
+    let target_dir = dir.path().join("target");
+    for path in &[
+        rustlib.join(host),
+        rustlib.join("x86_64-unknown-none"),
+        target_dir.clone(),
+    ] {
+        fs::create_dir_all(path)?;
+    }
+    let mut builder = SysrootBuilder::new(Sysroot::Core);
+    builder
+        .output(output.clone())
+        .target_dir(target_dir.clone())
+        .target("x86_64-unknown-none".into());
+
+    let clean = |builder: &SysrootBuilder, clean| builder.clean(&clean, true);
+    assert_eq!(
+        clean(&builder, Clean::All)?,
+        vec![output.clone(), target_dir.clone()]
+    );
+    assert_eq!(
+        clean(&builder, Clean::Target("x86_64-unknown-none".into()))?,
+        vec![rustlib.join("x86_64-unknown-none")]
+    );
+    assert_eq!(
+        clean(&builder, Clean::Target("custom.json".into()))?,
+        Vec::<PathBuf>::new()
+    );
+    assert_eq!(clean(&builder, Clean::BuildDir)?, vec![target_dir.clone()]);
+    assert_eq!(clean(&builder, Clean::HostTools)?, vec![rustlib.join(host)]);
+
+    // The host sysroot is built, not copied host tools.
+    builder.target(host.into());
+    let err = clean(&builder, Clean::HostTools).unwrap_err();
+    assert!(format!("{:#}", err).contains("no host tools to remove"));
+    assert_eq!(
+        clean(&builder, Clean::Target(host.into()))?,
+        vec![rustlib.join(host)]
+    );
+
+    assert!(rustlib.join(host).exists());
+    assert!(target_dir.exists());
+    Ok(())
+}
@@ -1,5 +1,21 @@
 use anyhow::{Context, Result};
-use cargo_sysroot::{Sysroot, SysrootBuilder};
+use cargo_sysroot::{
+    BuildEvent,
+    CToolchain,
+    CrateOverride,
+    CrateSettings,
+    CrateSource,
+    Features,
+    StdFeature,
+    Sysroot,
+    SysrootBuilder,
+};
+use std::{
+    env,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 /// Test that all targets compile as expected.
 #[test]
@@ -8,17 +24,497 @@ fn all_compile() -> Result<()> {
         Sysroot::Core,
         Sysroot::CompilerBuiltins,
         Sysroot::Alloc,
-        // Sysroot::Std,
+        Sysroot::Std,
+        Sysroot::Test,
     ] {
         let build_dir = tempfile::tempdir()?;
-        let sysroot = SysrootBuilder::new(*sys)
+        let mut builder = SysrootBuilder::new(*sys);
+        // The target unwinds on panic.
+        if *sys == Sysroot::Std || *sys == Sysroot::Test {
+            builder.std_features(&[StdFeature::PanicUnwind]);
+        }
+        let report = builder
             .output(build_dir.path().into())
             // .target("x86_64-unknown-uefi".into())
             .target("x86_64-unknown-linux-gnu".into())
             // .target("spirv-unknown-unknown".into())
             .build()
             .with_context(|| format!("Error compiling Sysroot: {:?}", sys))?;
-        eprintln!("Sysroot {:?}, path {}", sys, sysroot.display());
+        eprintln!("Sysroot {:?}, path {}", sys, report.sysroot.display());
+        assert!(!report.targets[0].artifacts.is_empty());
+    }
+    Ok(())
+}
+
+/// Test that extra library crates can be added to a sysroot, but not the
+/// [`Sysroot`] ones.
+#[test]
+fn extra_crates() -> Result<()> {
+    let build_dir = tempfile::tempdir()?;
+    let report = SysrootBuilder::new(Sysroot::Core)
+        .output(build_dir.path().into())
+        .target("x86_64-unknown-linux-gnu".into())
+        .extra_crates(vec!["compiler_builtins", "panic_abort"])
+        .build()?;
+    let crates: Vec<_> = report.targets[0]
+        .crates
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect();
+    assert!(crates.contains(&"compiler_builtins"));
+    assert!(crates.contains(&"panic_abort"));
+
+    let err = SysrootBuilder::new(Sysroot::Core)
+        .output(build_dir.path().into())
+        .target("x86_64-unknown-linux-gnu".into())
+        .extra_crates(vec!["alloc"])
+        .build()
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("--crates alloc"));
+    Ok(())
+}
+
+/// Test that the `c` feature is checked for before building, whenever
+/// `compiler_builtins` is built.
+#[test]
+fn compiler_builtins_c() -> Result<()> {
+    let build_dir = tempfile::tempdir()?;
+    let mut builder = SysrootBuilder::new(Sysroot::Core);
+    builder
+        .output(build_dir.path().into())
+        .target("x86_64-unknown-linux-gnu".into())
+        .features(&[Features::CompilerBuiltinsC])
+        .extra_crates(vec!["compiler_builtins"]);
+
+    let err = builder
+        .clone()
+        .c_toolchain(
+            "x86_64-unknown-linux-gnu",
+            CToolchain {
+                cc: Some("/nonexistent/cc".into()),
+                ..Default::default()
+            },
+        )
+        .build()
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("No C compiler for `x86_64-unknown-linux-gnu`"));
+
+    if env::var_os("RUST_COMPILER_RT_ROOT").is_none()
+        && env::var_os("LLVM_COMPILER_RT_LIB").is_none()
+    {
+        let err = builder.build().unwrap_err();
+        assert!(format!("{:#}", err).contains("RUST_COMPILER_RT_ROOT"));
+    }
+    Ok(())
+}
+
+/// Test that the profiles `panic` setting is used for the panic runtime
+/// check, so `std` can abort on a target that unwinds.
+#[test]
+fn profile_panic() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let manifest = dir.path().join("Cargo.toml");
+    fs::write(
+        &manifest,
+        "[package]\nname = \"test\"\nversion = \"0.1.0\"\n\n\
+         [profile.release]\npanic = \"abort\"\n",
+    )?;
+    let mut builder = SysrootBuilder::new(Sysroot::Std);
+    builder
+        .manifest(manifest)
+        .output(dir.path().join("sysroot"))
+        .target("x86_64-unknown-linux-gnu".into());
+
+    let err = builder
+        .clone()
+        .std_features(&[StdFeature::PanicUnwind])
+        .build()
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("panic strategy is `abort`"));
+
+    let report = builder.build()?;
+    assert!(!report.targets[0].artifacts.is_empty());
+    Ok(())
+}
+
+/// Write a stand-in `compiler_builtins` to `dir`, with `lib_rs`, and a
+/// build dependency `hostdep` with `hostdep_rs`.
+fn fake_compiler_builtins(dir: &Path, lib_rs: &str, hostdep_rs: &str) -> Result<PathBuf> {
+    let fake = dir.join("compiler_builtins");
+    fs::create_dir_all(fake.join("src"))?;
+    fs::create_dir_all(fake.join("hostdep").join("src"))?;
+    fs::write(
+        fake.join("Cargo.toml"),
+        "[package]\nname = \"compiler_builtins\"\nversion = \"0.1.0\"\n\n\
+         [features]\ncompiler-builtins = []\nrustc-dep-of-std = []\nmem = []\n\n\
+         [build-dependencies]\nhostdep = { path = \"hostdep\" }\n",
+    )?;
+    fs::write(
+        fake.join("src").join("lib.rs"),
+        format!("#![feature(no_core)]\n#![no_core]\n{}", lib_rs),
+    )?;
+    fs::write(fake.join("build.rs"), "fn main() {\n    hostdep::f();\n}\n")?;
+    fs::write(
+        fake.join("hostdep").join("Cargo.toml"),
+        "[package]\nname = \"hostdep\"\nversion = \"0.1.0\"\n",
+    )?;
+    fs::write(
+        fake.join("hostdep").join("src").join("lib.rs"),
+        format!("{}pub fn f() {{}}\n", hostdep_rs),
+    )?;
+    Ok(fake)
+}
+
+/// Test that build dependencies, which are built for the host, don't end up
+/// in the sysroot.
+#[test]
+fn no_host_artifacts() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fake = fake_compiler_builtins(dir.path(), "", "")?;
+    let report = SysrootBuilder::new(Sysroot::CompilerBuiltins)
+        .output(dir.path().join("sysroot"))
+        .target("x86_64-unknown-linux-gnu".into())
+        .override_crate("compiler_builtins", CrateOverride::Path { path: fake })
+        .build()?;
+    let report = &report.targets[0];
+    assert!(report.crates.iter().any(|c| c.name == "compiler_builtins"));
+    assert!(report.crates.iter().all(|c| c.name != "hostdep"));
+    assert!(report
+        .artifacts
+        .iter()
+        .all(|a| !a.to_string_lossy().contains("hostdep")));
+    Ok(())
+}
+
+/// Test that per-crate rustflags only reach that crate, and need a rustc
+/// wrapper.
+#[test]
+fn crate_rustflags() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    // Unknown features are an error, so each only builds with the right cfg.
+    let fake = fake_compiler_builtins(
+        dir.path(),
+        "#![cfg_attr(not(my_cfg), feature(missing_my_cfg))]\n",
+        "#![cfg_attr(my_cfg, feature(leaked_my_cfg))]\n",
+    )?;
+    let mut builder = SysrootBuilder::new(Sysroot::CompilerBuiltins);
+    builder
+        .output(dir.path().join("sysroot"))
+        .target("x86_64-unknown-linux-gnu".into())
+        .override_crate("compiler_builtins", CrateOverride::Path { path: fake })
+        .crate_settings(
+            "compiler_builtins",
+            CrateSettings {
+                rustflags: vec!["--cfg".into(), "my_cfg".into()],
+                ..Default::default()
+            },
+        );
+
+    let err = builder.build().unwrap_err();
+    assert!(format!("{:#}", err).contains("SysrootBuilder::rustc_wrapper"));
+
+    builder
+        .rustc_wrapper(env!("CARGO_BIN_EXE_cargo-sysroot").into())
+        .build()?;
+    Ok(())
+}
+
+/// Test that an unchanged sysroot isn't rebuilt, and a changed one is.
+#[test]
+fn fingerprint() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fake = fake_compiler_builtins(dir.path(), "", "")?;
+    let mut builder = SysrootBuilder::new(Sysroot::CompilerBuiltins);
+    builder
+        .output(dir.path().join("sysroot"))
+        .target("x86_64-unknown-linux-gnu".into())
+        .override_crate(
+            "compiler_builtins",
+            CrateOverride::Path { path: fake.clone() },
+        );
+    let up_to_date =
+        |builder: &SysrootBuilder| -> Result<bool> { Ok(builder.build()?.targets[0].up_to_date) };
+    assert!(!up_to_date(&builder)?);
+    assert!(up_to_date(&builder)?);
+
+    // Building the override elsewhere doesn't matter.
+    fs::create_dir_all(fake.join("target"))?;
+    fs::write(fake.join("target").join("out"), "")?;
+    fs::create_dir_all(fake.join(".git"))?;
+    assert!(up_to_date(&builder)?);
+
+    fs::write(
+        fake.join("src").join("lib.rs"),
+        "#![feature(no_core)]\n#![no_core]\n// Changed\n",
+    )?;
+    assert!(!up_to_date(&builder)?);
+    assert!(up_to_date(&builder)?);
+
+    builder.rustc_flags(["-Cdebuginfo=1"]);
+    assert!(!up_to_date(&builder)?);
+    Ok(())
+}
+
+/// Test that the observer sees each phase of a build, in order.
+#[test]
+fn observer() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fake = fake_compiler_builtins(dir.path(), "", "")?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let seen = events.clone();
+    let mut builder = SysrootBuilder::new(Sysroot::CompilerBuiltins);
+    builder
+        .output(dir.path().join("sysroot"))
+        .target("x86_64-unknown-linux-gnu".into())
+        .override_crate("compiler_builtins", CrateOverride::Path { path: fake })
+        .observer(move |event: &BuildEvent<'_>| {
+            let event = match event {
+                BuildEvent::RustSrc(_) => "rust-src".into(),
+                BuildEvent::GenerateManifest(_) => "manifest".into(),
+                BuildEvent::Override { name, .. } => format!("override {}", name),
+                BuildEvent::UpToDate { .. } => "up to date".into(),
+                BuildEvent::Building { .. } => "building".into(),
+                BuildEvent::Compiled {
+                    name,
+                    current,
+                    total,
+                    ..
+                } => format!("compiled {} {}/{}", name, current, total),
+                BuildEvent::CopyArtifacts { .. } => "copy artifacts".into(),
+                BuildEvent::CopyHostTools => "copy host tools".into(),
+                _ => return,
+            };
+            seen.lock().unwrap().push(event);
+        });
+
+    builder.build()?;
+    assert_eq!(
+        events.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![
+            "rust-src",
+            "manifest",
+            "override compiler_builtins",
+            "building",
+            "compiled compiler_builtins 1/1",
+            "copy artifacts",
+        ]
+    );
+
+    builder.build()?;
+    assert_eq!(
+        events.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec!["rust-src", "up to date"]
+    );
+    Ok(())
+}
+
+/// Test that artifacts are staged, an interrupted replacement is recovered,
+/// and a build waits for the lock held by another.
+#[test]
+fn staging_and_lock() -> Result<()> {
+    use fs2::FileExt;
+    use std::{sync::mpsc, thread};
+
+    let dir = tempfile::tempdir()?;
+    let fake = fake_compiler_builtins(dir.path(), "", "")?;
+    let sysroot = dir.path().join("sysroot");
+    let mut builder = SysrootBuilder::new(Sysroot::CompilerBuiltins);
+    builder
+        .output(sysroot.clone())
+        .target("x86_64-unknown-linux-gnu".into())
+        .override_crate("compiler_builtins", CrateOverride::Path { path: fake });
+    let report = builder.build()?;
+    let artifacts = &report.targets[0].artifacts;
+    assert!(!artifacts.is_empty());
+    assert!(artifacts.iter().all(|a| a.exists()));
+    assert!(!sysroot
+        .join(".staging")
+        .join("x86_64-unknown-linux-gnu")
+        .exists());
+
+    // As if interrupted between the two renames in `replace_dir`.
+    let lib = sysroot
+        .join("lib")
+        .join("rustlib")
+        .join("x86_64-unknown-linux-gnu")
+        .join("lib");
+    fs::rename(&lib, lib.with_file_name("lib.old"))?;
+    assert!(builder.build()?.targets[0].up_to_date);
+    assert!(artifacts.iter().all(|a| a.exists()));
+    assert!(!lib.with_file_name("lib.old").exists());
+
+    let lock = fs::File::create(sysroot.join(".cargo-sysroot.lock"))?;
+    lock.lock_exclusive()?;
+    let (waiting, waited) = mpsc::channel();
+    let waiting = Mutex::new(waiting);
+    builder.observer(move |event: &BuildEvent<'_>| {
+        if let BuildEvent::WaitingForLock(_) = event {
+            waiting.lock().unwrap().send(()).unwrap();
+        }
+    });
+    let build = thread::spawn(move || builder.build().map(|r| r.targets[0].up_to_date));
+    waited.recv()?;
+    assert!(!build.is_finished());
+    lock.unlock()?;
+    assert!(build.join().unwrap()?);
+    Ok(())
+}
+
+/// A [`SysrootBuilder`] for a stand-in `compiler_builtins`, with a build
+/// dependency `sysroot_vendored` from crates.io, and a vendor directory with
+/// each of its `versions`.
+///
+/// The vendor directory isn't used, and nothing can be downloaded.
+fn vendored_builder(dir: &Path, versions: &[&str]) -> Result<SysrootBuilder> {
+    let fake = fake_compiler_builtins(dir, "", "")?;
+    // `[build-dependencies]` is the last table.
+    let mut manifest = fs::read_to_string(fake.join("Cargo.toml"))?;
+    manifest.push_str("sysroot_vendored = \"0.1\"\n");
+    fs::write(fake.join("Cargo.toml"), manifest)?;
+
+    for version in versions {
+        let vendored = dir
+            .join("vendor")
+            .join(format!("sysroot_vendored-{}", version));
+        fs::create_dir_all(vendored.join("src"))?;
+        fs::write(
+            vendored.join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"sysroot_vendored\"\nversion = \"{}\"\n",
+                version
+            ),
+        )?;
+        fs::write(vendored.join("src").join("lib.rs"), "")?;
+        fs::write(
+            vendored.join(".cargo-checksum.json"),
+            r#"{"files":{},"package":null}"#,
+        )?;
+    }
+
+    let mut builder = SysrootBuilder::new(Sysroot::CompilerBuiltins);
+    builder
+        .output(dir.join("sysroot"))
+        .target("x86_64-unknown-linux-gnu".into())
+        .override_crate("compiler_builtins", CrateOverride::Path { path: fake })
+        .offline(true);
+    Ok(builder)
+}
+
+/// Test that crates.io dependencies come from a vendor directory.
+#[test]
+fn vendor() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut builder = vendored_builder(dir.path(), &["0.1.0"])?;
+    let err = builder.clone().build().unwrap_err();
+    assert!(format!("{:#}", err).contains("no matching package named `sysroot_vendored`"));
+
+    builder
+        .crate_source(CrateSource::Vendor(dir.path().join("vendor")))
+        .build()?;
+    let lockfile = fs::read_to_string(dir.path().join("sysroot").join("Cargo.lock"))?;
+    assert!(lockfile.contains("name = \"sysroot_vendored\""));
+    Ok(())
+}
+
+/// Test that the sysroot starts from the given lockfile, which `locked`
+/// requires be up to date.
+#[test]
+fn lockfile() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut builder = vendored_builder(dir.path(), &["0.1.0", "0.1.1"])?;
+    builder.crate_source(CrateSource::Vendor(dir.path().join("vendor")));
+    let sysroot_lock = dir.path().join("sysroot").join("Cargo.lock");
+    let version = |lockfile: &str| -> Result<String> {
+        let lockfile: toml::Value = toml::from_str(lockfile)?;
+        let package = lockfile["package"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|p| p["name"].as_str() == Some("sysroot_vendored"))
+            .context("sysroot_vendored wasn't locked")?;
+        Ok(package["version"].as_str().unwrap().into())
+    };
+
+    let err = builder.clone().locked(true).build().unwrap_err();
+    assert!(format!("{:#}", err).contains("requires SysrootBuilder::lockfile"));
+
+    builder.build()?;
+    let lockfile = fs::read_to_string(&sysroot_lock)?;
+    assert_eq!(version(&lockfile)?, "0.1.1");
+
+    let pinned = dir.path().join("pinned.lock");
+    fs::write(
+        &pinned,
+        lockfile.replace(
+            "name = \"sysroot_vendored\"\nversion = \"0.1.1\"",
+            "name = \"sysroot_vendored\"\nversion = \"0.1.0\"",
+        ),
+    )?;
+    builder.lockfile(pinned).locked(true).force(true).build()?;
+    assert_eq!(version(&fs::read_to_string(&sysroot_lock)?)?, "0.1.0");
+
+    // Missing `sysroot_vendored`, so cargo would need to update it.
+    let stale = dir.path().join("stale.lock");
+    fs::write(
+        &stale,
+        toml::to_string(&toml::from_str::<toml::Value>(&lockfile).map(|mut lock| {
+            let packages = lock["package"].as_array_mut().unwrap();
+            packages.retain(|p| p["name"].as_str() != Some("sysroot_vendored"));
+            lock
+        })?)?,
+    )?;
+    let err = builder.lockfile(stale).build().unwrap_err();
+    assert!(format!("{:#}", err).contains("because --locked was passed"));
+    Ok(())
+}
+
+/// Test that the sysroot can be built through a symlink.
+#[cfg(unix)]
+#[test]
+fn symlinked_output() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fake = fake_compiler_builtins(dir.path(), "", "")?;
+    fs::create_dir(dir.path().join("real"))?;
+    std::os::unix::fs::symlink(dir.path().join("real"), dir.path().join("link"))?;
+    let report = SysrootBuilder::new(Sysroot::CompilerBuiltins)
+        .output(
+            dir.path()
+                .join("link")
+                .join("..")
+                .join("link")
+                .join("sysroot"),
+        )
+        .target("x86_64-unknown-linux-gnu".into())
+        .override_crate("compiler_builtins", CrateOverride::Path { path: fake })
+        .build()?;
+    let report = &report.targets[0];
+    assert!(report.crates.iter().any(|c| c.name == "compiler_builtins"));
+    assert!(!report.artifacts.is_empty());
+    assert!(report.artifacts.iter().all(|a| a.exists()));
+    Ok(())
+}
+
+/// Test that the sysroot is built with the chosen profile, and not with the
+/// ones cargo treats specially.
+#[test]
+fn profile() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let fake = fake_compiler_builtins(dir.path(), "", "")?;
+    let mut builder = SysrootBuilder::new(Sysroot::CompilerBuiltins);
+    builder
+        .output(dir.path().join("sysroot"))
+        .target("x86_64-unknown-linux-gnu".into())
+        .override_crate("compiler_builtins", CrateOverride::Path { path: fake });
+
+    let err = builder.clone().profile("test").build().unwrap_err();
+    assert!(format!("{:#}", err).contains("`test` profile can't be used"));
+
+    let report = builder.profile("dev").build()?;
+    let out = builder.build_dir().join("x86_64-unknown-linux-gnu");
+    assert!(!out.join("release").exists());
+    for artifact in &report.targets[0].artifacts {
+        let name = artifact.file_name().unwrap();
+        assert!(out.join("debug").join("deps").join(name).exists());
     }
     Ok(())
 }
@@ -0,0 +1,259 @@
+use anyhow::Result;
+use cargo_sysroot::{CToolchain, CrateOverride, Features, Sysroot, SysrootBuilder, SysrootConfig};
+use std::{fs, path::PathBuf};
+
+const PACKAGE: &str = r#"
+[package]
+name = "test"
+version = "0.1.0"
+"#;
+
+/// Test that `package.metadata.cargo-sysroot` is parsed, and paths are
+/// relative to the manifest.
+#[test]
+fn from_manifest() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let manifest = dir.path().join("Cargo.toml");
+    fs::write(
+        &manifest,
+        format!(
+            "{}{}",
+            PACKAGE,
+            r#"
+[package.metadata.cargo-sysroot]
+target = ["x86_64-unknown-uefi", "custom.json"]
+crates = "core"
+features = ["no-asm"]
+default-features = false
+rust-src = "library"
+
+[package.metadata.cargo-sysroot.overrides]
+core = { path = "fork/core" }
+
+[package.metadata.cargo-sysroot.c-toolchain.x86_64-unknown-uefi]
+cc = "clang"
+cflags = ["-ffreestanding"]
+
+[package.metadata.cargo-sysroot.crate-settings.core]
+opt-level = 3
+rustflags = ["--cfg", "my_cfg"]
+"#
+        ),
+    )?;
+    let config = SysrootConfig::from_manifest(&manifest)?;
+    assert_eq!(
+        config.target,
+        vec![
+            PathBuf::from("x86_64-unknown-uefi"),
+            dir.path().join("custom.json")
+        ]
+    );
+    assert_eq!(config.crates, Some(Sysroot::Core));
+    assert_eq!(config.all_features(), vec![Features::CompilerBuiltinsNoAsm]);
+    assert_eq!(config.rust_src, Some(dir.path().join("library")));
+    assert_eq!(config.output, None);
+    assert_eq!(
+        config.overrides["core"],
+        CrateOverride::Path {
+            path: dir.path().join("fork/core")
+        }
+    );
+    assert_eq!(
+        config.c_toolchain["x86_64-unknown-uefi"],
+        CToolchain {
+            cc: Some("clang".into()),
+            ar: None,
+            cflags: vec!["-ffreestanding".into()],
+        }
+    );
+    let core = &config.crate_settings["core"];
+    assert_eq!(core.rustflags, vec!["--cfg", "my_cfg"]);
+    assert_eq!(core.profile["opt-level"].as_integer(), Some(3));
+    Ok(())
+}
+
+/// Test that unknown keys are rejected, and the error names them.
+#[test]
+fn unknown_key() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let manifest = dir.path().join("Cargo.toml");
+    fs::write(
+        &manifest,
+        format!(
+            "{}{}",
+            PACKAGE, "\n[package.metadata.cargo-sysroot]\ntargte = \"x86_64-unknown-uefi\"\n"
+        ),
+    )?;
+    let err = SysrootConfig::from_manifest(&manifest).unwrap_err();
+    assert!(format!("{:#}", err).contains("unknown field `targte`"));
+    Ok(())
+}
+
+/// Test that overrides are either a path or git, and typos are named.
+#[test]
+fn overrides() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let manifest = dir.path().join("Cargo.toml");
+    let config = |overrides: &str| {
+        fs::write(
+            &manifest,
+            format!(
+                "{}\n[package.metadata.cargo-sysroot.overrides]\n{}",
+                PACKAGE, overrides
+            ),
+        )?;
+        SysrootConfig::from_manifest(&manifest)
+    };
+
+    let libc = config("libc = { git = \"https://example.com/libc\", branch = \"fix\" }\n")?;
+    assert_eq!(
+        libc.overrides["libc"],
+        CrateOverride::Git {
+            git: "https://example.com/libc".into(),
+            branch: Some("fix".into()),
+            tag: None,
+            rev: None,
+        }
+    );
+
+    for (overrides, error) in &[
+        (
+            "libc = { git = \"https://example.com/libc\", revv = \"abc\" }",
+            "unknown field `revv`, expected one of `path`, `git`, `branch`, `tag`, `rev`",
+        ),
+        (
+            "libc = { git = \"https://example.com/libc\", tag = \"v1\", rev = \"abc\" }",
+            "only one of `branch`, `tag`, or `rev`",
+        ),
+        (
+            "core = { path = \"core\", rev = \"abc\" }",
+            "only be used with `git`",
+        ),
+        (
+            "core = { path = \"core\", git = \"core\" }",
+            "can't both be set",
+        ),
+        ("core = {}", "expected `path` or `git`"),
+    ] {
+        let err = config(overrides).unwrap_err();
+        assert!(format!("{:#}", err).contains(error), "{:#}", err);
+    }
+    Ok(())
+}
+
+/// Test that workspace members inherit, and override,
+/// `workspace.metadata.cargo-sysroot`.
+#[test]
+fn from_workspace() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    fs::write(
+        dir.path().join("Cargo.toml"),
+        r#"
+[workspace]
+members = ["boot", "kernel"]
+
+[workspace.metadata.cargo-sysroot]
+target = "x86_64-unknown-none"
+crates = "core"
+"#,
+    )?;
+    for (name, metadata) in &[
+        ("boot", "target = \"x86_64-unknown-uefi\"\n"),
+        ("kernel", ""),
+    ] {
+        fs::create_dir_all(dir.path().join(name).join("src"))?;
+        fs::write(dir.path().join(name).join("src").join("lib.rs"), "")?;
+        fs::write(
+            dir.path().join(name).join("Cargo.toml"),
+            format!(
+                "[package]\nname = \"{}\"\nversion = \"0.1.0\"\n\n\
+                 [package.metadata.cargo-sysroot]\n{}",
+                name, metadata
+            ),
+        )?;
+    }
+    let mut workspace = SysrootConfig::from_workspace(&dir.path().join("Cargo.toml"))?;
+    workspace.members.sort_by(|a, b| a.0.cmp(&b.0));
+    let targets: Vec<_> = workspace
+        .members
+        .iter()
+        .map(|(name, c)| (name.as_str(), c.target.clone(), c.crates))
+        .collect();
+    assert_eq!(
+        targets,
+        vec![
+            (
+                "boot",
+                vec![PathBuf::from("x86_64-unknown-uefi")],
+                Some(Sysroot::Core)
+            ),
+            (
+                "kernel",
+                vec![PathBuf::from("x86_64-unknown-none")],
+                Some(Sysroot::Core)
+            ),
+        ]
+    );
+    // Same configuration, so one sysroot for both targets.
+    assert_eq!(workspace.builders()?.len(), 1);
+
+    let member = SysrootConfig::from_workspace(&dir.path().join("kernel").join("Cargo.toml"))?;
+    assert_eq!(member.members.len(), 1);
+    Ok(())
+}
+
+/// Test that sysroot paths resolve relative to the manifest, without
+/// building anything.
+#[test]
+fn paths() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let manifest = dir.path().join("Cargo.toml");
+    fs::write(
+        &manifest,
+        format!(
+            "{}{}",
+            PACKAGE,
+            "\n[package.metadata.cargo-sysroot]\ntarget = \"custom.json\"\noutput = \"sysroot\"\n"
+        ),
+    )?;
+    let builder = SysrootBuilder::from_manifest(&manifest)?;
+    let sysroot = dir.path().canonicalize()?.join("sysroot");
+    assert_eq!(builder.sysroot_dir()?, sysroot);
+    assert_eq!(
+        builder.target_libdir(&builder.target_list()[0])?,
+        sysroot.join("lib/rustlib/custom/lib")
+    );
+    assert!(!sysroot.exists());
+    Ok(())
+}
+
+/// Test that `package.metadata.cargo-sysroot.profile` is merged over the
+/// manifest profiles, without building anything.
+#[test]
+fn profiles() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let manifest = dir.path().join("Cargo.toml");
+    fs::write(
+        &manifest,
+        format!(
+            "{}{}",
+            PACKAGE,
+            r#"
+[profile.release]
+panic = "abort"
+lto = true
+opt-level = 3
+
+[package.metadata.cargo-sysroot.profile.release]
+lto = false
+opt-level = 2
+"#
+        ),
+    )?;
+    let profiles = SysrootBuilder::from_manifest(&manifest)?.profiles()?;
+    let release = &profiles["release"];
+    assert_eq!(release["panic"].as_str(), Some("abort"));
+    assert_eq!(release["lto"].as_bool(), Some(false));
+    assert_eq!(release["opt-level"].as_integer(), Some(2));
+    Ok(())
+}